@@ -0,0 +1,274 @@
+use std::{fs, path::Path, process};
+
+use fall::config;
+use fall::grid::{self, ModuleDef, Tile, GRID_HEIGHT, GRID_WIDTH};
+use fall::reach;
+use fall::simulation;
+
+const USAGE: &str = "Usage: fall-modules lint <modules file> [--config <config file>]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("lint") => lint(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            println!("{}", err);
+            process::exit(2);
+        }
+    }
+}
+
+/// Collects the problems found in a single module
+#[derive(Default)]
+struct Report {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+/// Returns whether the file is free of errors
+fn lint(args: &[String]) -> Result<bool, String> {
+    let mut path = None;
+    let mut config_path = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(iter.next().ok_or("--config needs a file")?),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    let path = Path::new(path.ok_or(USAGE)?);
+
+    // The jump height depends on the config, so use the one the game would
+    let config_path = match config_path {
+        Some(config_path) => Some(Path::new(config_path).to_path_buf()),
+        None => path
+            .parent()
+            .map(|dir| dir.join("config.toml"))
+            .filter(|x| x.exists()),
+    };
+    if let Some(config_path) = config_path {
+        let text = fs::read_to_string(&config_path)
+            .map_err(|err| format!("Could not read '{}': {}", config_path.display(), err))?;
+        config::load_str(&text)
+            .map_err(|err| format!("Error loading '{}' {}", config_path.display(), err))?;
+    }
+
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("Could not read '{}': {}", path.display(), err))?;
+    let lines: Vec<&str> = text.lines().collect();
+    let (mut errors, mut warnings) = (0, 0);
+    // Modules that can't be read are reported, and the rest still get checked
+    let mut modules = Vec::new();
+    for module in grid::parse_each_module(&text) {
+        match module {
+            Ok(module) => modules.push(module),
+            Err(err) => {
+                println!("{}: error: {}", path.display(), err);
+                errors += 1;
+            }
+        }
+    }
+    if modules.is_empty() && errors == 0 {
+        println!(
+            "{}: error: the file doesn't contain any modules",
+            path.display()
+        );
+        return Ok(false);
+    }
+
+    let lobby = grid::lobby_index(&modules);
+    for (i, module) in modules.iter().enumerate() {
+        let report = lint_module(module, i == lobby, &lines);
+        let solid = count_solid(module);
+        println!(
+            "{} '{}' (line {}): {} solid tiles",
+            if i == lobby { "lobby" } else { "module" },
+            module.name,
+            module.line.unwrap_or(0),
+            solid,
+        );
+        for error in &report.errors {
+            println!("    error: {}", error);
+        }
+        for warning in &report.warnings {
+            println!("    warning: {}", warning);
+        }
+        errors += report.errors.len();
+        warnings += report.warnings.len();
+    }
+    println!(
+        "{} modules, {} errors, {} warnings",
+        modules.len(),
+        errors,
+        warnings
+    );
+    Ok(errors == 0)
+}
+
+fn lint_module(module: &ModuleDef, is_lobby: bool, lines: &[&str]) -> Report {
+    let mut report = Report::default();
+    let first_line = module.line.unwrap_or(1);
+    for row in 0..GRID_HEIGHT {
+        let line = first_line + row;
+        let text = lines.get(line - 1).cloned().unwrap_or("");
+        // Trailing whitespace is air to the parser, so it only counts
+        // towards rows that are too short
+        let width = text.trim_end().chars().count();
+        let short = text.chars().count() < GRID_WIDTH;
+        if width > GRID_WIDTH || short {
+            report.warnings.push(format!(
+                "line {}: the row is {} tiles wide instead of {}",
+                line,
+                if short { text.chars().count() } else { width },
+                GRID_WIDTH
+            ));
+        }
+    }
+
+    if count_solid(module) == 0 {
+        report.errors.push("there are no solid tiles".into());
+    } else if count_destructible(module) == 0 {
+        report
+            .errors
+            .push("nothing can be destroyed, so the grid will never fall".into());
+    }
+
+    let mut rng = simulation::new_rng(0);
+    if grid::find_spawn_location(module.tiles, &mut rng).is_none() {
+        report.errors.push(
+            "players can't spawn anywhere, they need 3 tiles of floor with 2 free above".into(),
+        );
+    }
+
+    check_lobby_tiles(module, is_lobby, &mut report);
+
+    let spots = reach::unreachable_spots(&module.tiles);
+    if let Some(&(x, y)) = spots.first() {
+        report.warnings.push(format!(
+            "{} spots can't be reached with a double jump, the first is line {} column {}",
+            spots.len(),
+            line_of(module, y),
+            x + 1
+        ));
+    }
+    report
+}
+
+/// Start buttons have to be written as `[`, any number of `!`, then `]`, and
+/// they and the leave tile only do anything in the lobby
+fn check_lobby_tiles(module: &ModuleDef, is_lobby: bool, report: &mut Report) {
+    let mut buttons = 0;
+    let mut leave_tiles = 0;
+    for (y, row) in module.tiles.iter().enumerate() {
+        let mut in_button = false;
+        let mut button_width = 0;
+        for (x, tile) in row.iter().enumerate() {
+            let at = format!("line {} column {}", line_of(module, y), x + 1);
+            match tile {
+                Tile::Start(0) if in_button => {
+                    report
+                        .errors
+                        .push(format!("{}: '[' inside a start button", at));
+                }
+                Tile::Start(0) => {
+                    in_button = true;
+                    button_width = 0;
+                }
+                Tile::Start(1) if in_button => button_width += 1,
+                Tile::Start(2) if in_button => {
+                    if button_width == 0 {
+                        report.errors.push(format!(
+                            "{}: a start button needs at least one '!' between '[' and ']'",
+                            at
+                        ));
+                    }
+                    in_button = false;
+                    buttons += 1;
+                }
+                Tile::Start(_) => {
+                    report
+                        .errors
+                        .push(format!("{}: start button tile outside of '[' and ']'", at));
+                }
+                Tile::Leave => leave_tiles += 1,
+                _ if in_button => {
+                    report
+                        .errors
+                        .push(format!("{}: start button isn't closed with ']'", at));
+                    in_button = false;
+                }
+                _ => (),
+            }
+        }
+        if in_button {
+            report.errors.push(format!(
+                "line {}: start button isn't closed with ']'",
+                line_of(module, y)
+            ));
+        }
+    }
+
+    if is_lobby {
+        if buttons == 0 {
+            report
+                .errors
+                .push("the lobby needs a start button so players can get ready".into());
+        }
+        if leave_tiles == 0 {
+            report
+                .warnings
+                .push("the lobby has no '?' tile, so players can't leave".into());
+        }
+    } else {
+        if buttons > 0 {
+            report.warnings.push(
+                "start buttons only work in the lobby, here they are unbreakable blocks".into(),
+            );
+        }
+        if leave_tiles > 0 {
+            report
+                .warnings
+                .push("'?' tiles only work in the lobby, here they do nothing".into());
+        }
+    }
+}
+
+/// The line a row of the module was written on, rows are stored bottom up
+/// but written top down
+fn line_of(module: &ModuleDef, y: usize) -> usize {
+    module.line.unwrap_or(1) + GRID_HEIGHT - 1 - y
+}
+
+fn count_solid(module: &ModuleDef) -> usize {
+    module
+        .tiles
+        .iter()
+        .flat_map(|row| row.iter())
+        .filter(|x| match x {
+            Tile::Solid(_) | Tile::Reinforced(_) | Tile::Explosive(_) | Tile::Surface(..) => true,
+            Tile::Regen(_) | Tile::Bedrock => true,
+            _ => false,
+        })
+        .count()
+}
+
+/// Bedrock, start buttons, leave tiles and some hazards can't be shot away
+fn count_destructible(module: &ModuleDef) -> usize {
+    module
+        .tiles
+        .iter()
+        .flat_map(|row| row.iter())
+        .filter(|x| match x {
+            Tile::Solid(_) | Tile::Reinforced(_) | Tile::Explosive(_) | Tile::Surface(..) => true,
+            Tile::Regen(_) | Tile::OneWay(_) => true,
+            Tile::Hazard(hazard, _) => hazard.info().destructible,
+            _ => false,
+        })
+        .count()
+}
@@ -0,0 +1,266 @@
+use ggez::graphics::{Point2, Vector2};
+use rand::Rng;
+
+use crate::config::PLAYER;
+use crate::draw;
+use crate::grid::{Grid, Tile};
+use crate::player::{ControlState, Player};
+use crate::simulation::{self, GameRng, Simulation};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    pub fn next(self) -> Difficulty {
+        match self {
+            Difficulty::Easy => Difficulty::Normal,
+            Difficulty::Normal => Difficulty::Hard,
+            Difficulty::Hard => Difficulty::Easy,
+        }
+    }
+
+    /// Ticks between the bot re-planning what it wants to do
+    fn reaction(self) -> u32 {
+        match self {
+            Difficulty::Easy => 24,
+            Difficulty::Normal => 12,
+            Difficulty::Hard => 4,
+        }
+    }
+
+    /// Chance of pulling the trigger when an opponent is lined up
+    fn trigger_chance(self) -> f64 {
+        match self {
+            Difficulty::Easy => 0.3,
+            Difficulty::Normal => 0.6,
+            Difficulty::Hard => 1.0,
+        }
+    }
+
+    /// Chance of jumping over a bullet headed for the bot
+    fn dodge_chance(self) -> f64 {
+        match self {
+            Difficulty::Easy => 0.0,
+            Difficulty::Normal => 0.3,
+            Difficulty::Hard => 0.8,
+        }
+    }
+}
+
+/// A computer player that produces a `ControlState` every tick from what it
+/// can see of the simulation.
+pub struct Bot {
+    pub difficulty: Difficulty,
+    slot: usize,
+    rng: GameRng,
+    // The plan, refreshed every `Difficulty::reaction` ticks
+    next_plan: u32,
+    move_dir: f32,
+    facing: f32,
+    shoot: bool,
+    want_up: bool,
+    jump_held: bool,
+}
+
+impl Bot {
+    pub fn new(slot: usize, difficulty: Difficulty, seed: u64) -> Bot {
+        Bot {
+            difficulty,
+            slot,
+            rng: simulation::new_rng(seed ^ slot as u64),
+            next_plan: 0,
+            move_dir: 0.0,
+            facing: 1.0,
+            shoot: false,
+            want_up: false,
+            jump_held: false,
+        }
+    }
+
+    pub fn think(&mut self, sim: &Simulation) -> ControlState {
+        let me = match &sim.players[self.slot] {
+            Some(player) if player.alive && !sim.in_menu => player,
+            _ => {
+                self.next_plan = 0;
+                self.jump_held = false;
+                return ControlState {
+                    facing: self.facing,
+                    ..ControlState::default()
+                };
+            }
+        };
+
+        if self.next_plan == 0 {
+            self.plan(sim, me);
+            self.next_plan = self.difficulty.reaction();
+        }
+        self.next_plan -= 1;
+
+        let want_jump = self.want_jump(sim, me);
+        // Jumps only happen on a rising edge and get cut short when let go of,
+        // so hold on while rising and let go before the next one
+        let jump = if self.jump_held {
+            me.vel().y > 0.0
+        } else {
+            want_jump
+        };
+        self.jump_held = jump;
+
+        ControlState {
+            lr: self.move_dir,
+            jump,
+            shoot: self.shoot,
+            repair: false,
+            l_pressed: self.move_dir < 0.0,
+            r_pressed: self.move_dir > 0.0,
+            facing: self.facing,
+            ..ControlState::default()
+        }
+    }
+
+    fn plan(&mut self, sim: &Simulation, me: &Player) {
+        let pos = me.pos();
+        let target = sim
+            .players
+            .iter()
+            .filter_map(|x| x.as_ref())
+            .filter(|x| x.alive && x.team != me.team)
+            .min_by(|a, b| {
+                let da = distance_squared(a.pos(), pos);
+                let db = distance_squared(b.pos(), pos);
+                da.partial_cmp(&db).unwrap()
+            });
+
+        let target = match target {
+            Some(target) => target.pos(),
+            None => {
+                // Nobody to fight, wander towards the middle
+                self.shoot = false;
+                self.want_up = false;
+                self.move_dir = (draw::WORLD_WIDTH / 2.0 - pos.x).signum();
+                return;
+            }
+        };
+
+        let dx = target.x - pos.x;
+        let dy = target.y - pos.y;
+        let dir = if dx < 0.0 { -1.0 } else { 1.0 };
+
+        // The bullet covers [0.6, 0.8] above the shooter's feet, so it lines up
+        // with the opponent's body, or with the tiles they are standing on.
+        let hits_body = dy > -0.8 && dy < 0.2;
+        let hits_floor = dy > 0.6 && dy < 1.8;
+        let lined_up = (hits_body || hits_floor) && dx.abs() < 16.0;
+
+        self.want_up = dy > 1.5;
+        self.shoot = lined_up && self.rng.gen_bool(self.difficulty.trigger_chance());
+
+        if lined_up {
+            self.facing = dir;
+            // Keep a comfortable distance while shooting
+            self.move_dir = if dx.abs() < 3.0 {
+                -dir
+            } else if dx.abs() > 10.0 {
+                dir
+            } else {
+                0.0
+            };
+        } else {
+            self.move_dir = if dx.abs() > 0.5 { dir } else { 0.0 };
+            self.facing = dir;
+        }
+    }
+
+    fn want_jump(&mut self, sim: &Simulation, me: &Player) -> bool {
+        let pos = me.pos();
+        let center = pos.x + PLAYER.width / 2.0;
+        let grids = &sim.grids;
+
+        if me.grounded {
+            let ahead = center + self.move_dir * (PLAYER.width / 2.0 + 0.3);
+            let wall_ahead = self.move_dir != 0.0 && solid_at(grids, ahead, pos.y + 0.3);
+            let gap_ahead = self.move_dir != 0.0 && !ground_below(grids, ahead, pos.y, 3.0);
+            let headroom = !(1..4).any(|i| solid_at(grids, center, pos.y + i as f32));
+            if wall_ahead || (gap_ahead && !self.want_down(sim, me)) {
+                return true;
+            }
+            if self.want_up && headroom {
+                return true;
+            }
+        } else if me.can_jump() && me.vel().y < 1.0 {
+            // Use the second jump at the top of the first one, or to save ourselves
+            let over_void = !ground_below(grids, center, pos.y, draw::WORLD_HEIGHT);
+            if self.want_up || over_void || pos.y < 1.0 {
+                return true;
+            }
+        }
+
+        self.incoming_bullet(sim, me) && self.rng.gen_bool(self.difficulty.dodge_chance())
+    }
+
+    fn want_down(&self, sim: &Simulation, me: &Player) -> bool {
+        !self.want_up
+            && sim
+                .players
+                .iter()
+                .filter_map(|x| x.as_ref())
+                .any(|x| x.alive && x.team != me.team && x.pos().y < me.pos().y - 1.5)
+    }
+
+    fn incoming_bullet(&self, sim: &Simulation, me: &Player) -> bool {
+        let pos = me.pos();
+        sim.bullets.iter().any(|bullet| {
+            let dx = pos.x - bullet.pos.x;
+            bullet.team != me.team
+                && dx.abs() < 4.0
+                && dx.signum() == bullet.vel.x.signum()
+                && (bullet.pos.y - pos.y - 0.4).abs() < 0.6
+        })
+    }
+}
+
+fn distance_squared(a: Point2, b: Point2) -> f32 {
+    let d: Vector2 = a - b;
+    d.x * d.x + d.y * d.y
+}
+
+fn solid_at(grids: &[Grid], x: f32, y: f32) -> bool {
+    grids
+        .iter()
+        .any(|grid| match grid.tile_at(Point2::new(x, y)) {
+            Some((Tile::Solid(health), ..))
+            | Some((Tile::Reinforced(health), ..))
+            | Some((Tile::Explosive(health), ..))
+            | Some((Tile::Regen(health), ..))
+            | Some((Tile::Surface(_, health), ..)) => health > 0,
+            Some((Tile::Start(_), ..)) | Some((Tile::Bedrock, ..)) => true,
+            _ => false,
+        })
+}
+
+/// Like `solid_at`, but also counts one way platforms, which can be stood on
+fn floor_at(grids: &[Grid], x: f32, y: f32) -> bool {
+    solid_at(grids, x, y)
+        || grids
+            .iter()
+            .any(|grid| match grid.tile_at(Point2::new(x, y)) {
+                Some((Tile::OneWay(health), ..)) => health > 0,
+                _ => false,
+            })
+}
+
+/// Is there a solid tile within `depth` tiles below a point?
+fn ground_below(grids: &[Grid], x: f32, y: f32, depth: f32) -> bool {
+    let mut probe = y - 0.5;
+    while probe > y - depth && probe > -1.0 {
+        if floor_at(grids, x, probe) {
+            return true;
+        }
+        probe -= 1.0;
+    }
+    false
+}
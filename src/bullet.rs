@@ -1,103 +1,267 @@
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+};
+
 use ggez::{
-    graphics::{self, DrawMode, Point2, Rect, Vector2},
+    graphics::{self, Color, DrawMode, Point2, Rect, Vector2},
     Context, GameResult,
 };
 
+use crate::collide::{self, Hit};
 use crate::draw;
-use crate::grid::{Grid, Tile};
+use crate::grid::{Grid, GridCoord, Surface, Tile};
 use crate::images::Images;
+use crate::math;
 use crate::player::{Player, Team};
-use crate::sound::{Sound, SoundEffect};
+use crate::simulation::Event;
+use crate::sound::SoundEffect;
+use crate::weapon;
 
 const BULLET_WIDTH: f32 = 0.2;
 const BULLET_HEIGHT: f32 = 0.2;
+/// Size of the buckets bullets get sorted into before checking them against
+/// each other, big enough that two bullets flying head on can't skip past
+/// each other's bucket in one tick
+const CELL_SIZE: f32 = 2.0;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Bullet {
     pub pos: Point2,
     pub vel: Vector2,
     pub team: Team,
+    /// The weapon that fired it
+    pub weapon: usize,
+    pub damage: u8,
+    pub tile_damage: u8,
+    /// Seconds left before it fizzles out
+    pub lifetime: f32,
+    /// Tiles it can still break through
+    pub pierce: u32,
+    /// Bounces left off of walls that can't be broken
+    pub ricochets: u32,
+    /// Speed the players it hits get pushed with
+    pub knockback: f32,
     pub is_alive: bool,
 }
 
 impl Bullet {
-    pub fn new(pos: Point2, vel: Vector2, team: Team) -> Self {
+    pub fn new(pos: Point2, vel: Vector2, team: Team, weapon: usize) -> Self {
+        let def = weapon::get(weapon);
         Bullet {
             pos,
             vel,
             team,
+            weapon,
+            damage: def.damage,
+            tile_damage: def.tile_damage,
+            lifetime: def.lifetime,
+            pierce: def.pierce,
+            ricochets: def.ricochets,
+            knockback: def.knockback,
             is_alive: true,
         }
     }
 
+    /// Feed all of the bullet's state into a checksum. Every field is listed
+    /// so new ones can't be left out by accident.
+    pub fn hash_state<H: Hasher>(&self, hasher: &mut H) {
+        let Bullet {
+            pos,
+            vel,
+            team,
+            weapon,
+            damage,
+            tile_damage,
+            lifetime,
+            pierce,
+            ricochets,
+            knockback,
+            is_alive,
+        } = self;
+        for value in &[pos.x, pos.y, vel.x, vel.y, *lifetime, *knockback] {
+            value.to_bits().hash(hasher);
+        }
+        (
+            team.0,
+            weapon,
+            damage,
+            tile_damage,
+            pierce,
+            ricochets,
+            is_alive,
+        )
+            .hash(hasher);
+    }
+
     pub fn rect(&self) -> Rect {
         Rect {
             x: self.pos.x - BULLET_WIDTH / 2.0,
-            y: self.pos.y + BULLET_HEIGHT / 2.0,
+            y: self.pos.y - BULLET_HEIGHT / 2.0,
             w: BULLET_WIDTH,
             h: BULLET_HEIGHT,
         }
     }
 
-    pub fn fixed_update<'a>(
+    pub fn fixed_update(
         &mut self,
-        ctx: &mut Context,
-        sounds: &mut Sound,
+        events: &mut Vec<Event>,
         grids: &mut [Grid],
         players: &mut [Option<Player>],
         in_menu: bool,
     ) {
-        self.pos += crate::DT * self.vel;
+        self.lifetime -= crate::DT;
 
-        if self.pos.x < -1.0 || self.pos.x > 33.0 {
+        // Every bounce uses up a ricochet, so this always ends
+        let mut left = 1.0;
+        while left > 0.0 && self.is_alive {
+            left = self.travel(left, events, grids, players, in_menu);
+        }
+
+        // Aimed shots can leave through the top or bottom of the screen too
+        let outside_x = self.pos.x < -1.0 || self.pos.x > draw::WORLD_WIDTH + 1.0;
+        let outside_y = self.pos.y < -1.0 || self.pos.y > draw::WORLD_HEIGHT + 1.0;
+        if outside_x || outside_y || self.lifetime <= 0.0 {
             self.is_alive = false;
         }
+    }
+
+    /// Move for `fraction` of a tick, up to the first thing that stops or
+    /// bounces the bullet. Returns how much of the tick is left after a bounce.
+    fn travel(
+        &mut self,
+        fraction: f32,
+        events: &mut Vec<Event>,
+        grids: &mut [Grid],
+        players: &mut [Option<Player>],
+        in_menu: bool,
+    ) -> f32 {
+        let start = self.rect();
+        let motion = fraction * crate::DT * self.vel;
 
-        for player in players.iter_mut() {
+        // Everything the bullet would touch along the way, so fast bullets
+        // can't skip over anything thin, along with how far the bullet moves
+        // before touching it
+        let mut hits = Vec::new();
+        for (i, player) in players.iter().enumerate() {
             if let Some(player) = player {
-                if self.rect().overlaps(&player.rect()) && self.team != player.team {
-                    player.damage();
-                    self.is_alive = false;
+                if self.team == player.team {
+                    continue;
+                }
+                if let Some(hit) = contact(start, motion, player.rect()) {
+                    hits.push((hit, hit.time * motion, Target::Player(i)));
                 }
             }
         }
+        let mut tiles = Vec::new();
+        for (i, grid) in grids.iter().enumerate() {
+            // Tiles are swept against in the grid's frame, then carried back
+            // along with the grid to find where the bullet really is
+            let rel_motion = fraction * crate::DT * (self.vel - grid.vel);
+            tiles.clear();
+            grid.overlapping_tiles(math::swept_rect(start, rel_motion), &mut tiles);
+            for &(tile, x, y) in &tiles {
+                if let Some(hit) = contact(start, rel_motion, grid.tile_rect(x, y)) {
+                    let moved =
+                        hit.time * rel_motion + grid.vel * (hit.time * fraction * crate::DT);
+                    hits.push((hit, moved, Target::Tile(i, tile, x, y)));
+                }
+            }
+        }
+        // The sort is stable, so players still get hit before tiles at the
+        // same moment
+        hits.sort_by(|a, b| a.0.time.partial_cmp(&b.0.time).unwrap());
 
-        for grid in grids {
-            let mut tiles = Vec::new();
-            grid.overlapping_tiles(self.rect(), &mut tiles);
-            for (tile, x, y) in tiles {
-                match tile {
-                    Tile::Solid(health) => {
-                        grid.damage_tile(x, y);
-                        grid.damage_tile(x, y);
-                        self.is_alive = false;
-                        let sound_effect = match health {
-                            0 => SoundEffect::BreakBlock,
-                            1 | 2 => SoundEffect::DamageBlockMore,
-                            _ => SoundEffect::DamageBlock,
-                        };
-                        sounds.play_sound(ctx, sound_effect);
-                    }
-                    Tile::Start(_) => {
-                        if let Some(player) = &mut players[self.team.0 as usize] {
-                            if in_menu {
-                                player.ready = !player.ready;
-                            }
-                        }
-                        self.is_alive = false;
+        for (hit, moved, target) in hits {
+            match target {
+                Target::Player(i) => {
+                    if let Some(player) = &mut players[i] {
+                        let impulse = self.vel.normalize() * self.knockback;
+                        player.shot(impulse, self.damage, events);
                     }
-                    Tile::Leave => {
-                        if in_menu {
-                            players[self.team.0 as usize] = None;
-                        }
-                        self.is_alive = false;
+                    self.is_alive = false;
+                }
+                Target::Tile(_, tile, _, _) if self.ricochets > 0 && bounces_off(tile) => {
+                    // Step back off the surface so the rest of the move
+                    // doesn't start inside it
+                    self.pos += moved + hit.normal * collide::COLLISION_TOLERANCE;
+                    self.ricochet(hit.normal);
+                    return fraction * (1.0 - hit.time);
+                }
+                Target::Tile(i, tile, x, y) => {
+                    self.hit_tile(&mut grids[i], (tile, x, y), events, players, in_menu);
+                }
+            }
+            if !self.is_alive {
+                self.pos += moved;
+                return 0.0;
+            }
+        }
+        self.pos += motion;
+        0.0
+    }
+
+    fn hit_tile(
+        &mut self,
+        grid: &mut Grid,
+        (tile, x, y): (Tile, GridCoord, GridCoord),
+        events: &mut Vec<Event>,
+        players: &mut [Option<Player>],
+        in_menu: bool,
+    ) {
+        match tile {
+            Tile::Bedrock => self.is_alive = false,
+            Tile::Hazard(hazard, _) if !hazard.info().destructible => {
+                self.is_alive = false;
+            }
+            Tile::Solid(health)
+            | Tile::OneWay(health)
+            | Tile::Reinforced(health)
+            | Tile::Explosive(health)
+            | Tile::Regen(health)
+            | Tile::Surface(_, health)
+            | Tile::Hazard(_, health) => {
+                for _ in 0..self.tile_damage {
+                    grid.damage_tile(x, y);
+                }
+                // Piercing bullets keep going through the tiles they break
+                if grid.module[y][x] == Tile::Air && self.pierce > 0 {
+                    self.pierce -= 1;
+                } else {
+                    self.is_alive = false;
+                }
+                let sound_effect = match health {
+                    0 => SoundEffect::BreakBlock,
+                    1 | 2 => SoundEffect::DamageBlockMore,
+                    _ => SoundEffect::DamageBlock,
+                };
+                events.push(Event::Sound(sound_effect));
+            }
+            Tile::Start(_) => {
+                if let Some(player) = &mut players[self.team.0 as usize] {
+                    if in_menu {
+                        player.ready = !player.ready;
                     }
-                    _ => (),
                 }
+                self.is_alive = false;
+            }
+            Tile::Leave => {
+                if in_menu && players[self.team.0 as usize].is_some() {
+                    players[self.team.0 as usize] = None;
+                    events.push(Event::PlayerLeft(self.team));
+                }
+                self.is_alive = false;
             }
+            Tile::Air => (),
         }
     }
 
+    /// Reflect off the side of the tile that was hit
+    fn ricochet(&mut self, normal: Vector2) {
+        self.vel -= 2.0 * self.vel.dot(&normal) * normal;
+        self.ricochets -= 1;
+    }
+
     pub fn draw(&self, ctx: &mut Context, _images: &Images) -> GameResult<()> {
         let points = [
             Point2::new(-BULLET_WIDTH / 2.0, -BULLET_HEIGHT / 2.0),
@@ -106,6 +270,94 @@ impl Bullet {
             Point2::new(BULLET_WIDTH / 2.0, -BULLET_HEIGHT / 2.0),
         ];
         let mesh = graphics::Mesh::new_polygon(ctx, DrawMode::Fill, &points)?;
-        draw::draw(ctx, &mesh, self.pos, 0.0)
+        let [r, g, b, a] = weapon::get(self.weapon).color;
+        graphics::set_color(ctx, Color::new(r, g, b, a))?;
+        let result = draw::draw(ctx, &mesh, self.pos, 0.0);
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+        result
+    }
+}
+
+/// Something a bullet can run into
+enum Target {
+    Player(usize),
+    Tile(usize, Tile, GridCoord, GridCoord),
+}
+
+/// Like `collide::sweep`, but anything the bullet already overlaps counts as
+/// being hit straight away, head on
+fn contact(rect: Rect, motion: Vector2, fixed: Rect) -> Option<Hit> {
+    if rect.overlaps(&fixed) {
+        let normal = if motion.norm_squared() > 0.0 {
+            -motion.normalize()
+        } else {
+            Vector2::new(0.0, 0.0)
+        };
+        return Some(Hit { time: 0.0, normal });
     }
+    collide::sweep(rect, motion, fixed)
+}
+
+/// Tiles that ricocheting bullets bounce off instead of hitting
+fn bounces_off(tile: Tile) -> bool {
+    match tile {
+        Tile::Bedrock | Tile::Surface(Surface::Bounce, _) => true,
+        Tile::Hazard(hazard, _) => !hazard.info().destructible,
+        _ => false,
+    }
+}
+
+/// Bullets from different teams that run into each other both get destroyed.
+/// Bullets are bucketed by position first so only neighbours get compared.
+pub fn collide_bullets(bullets: &mut [Bullet]) {
+    let mut cells: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+    for (i, bullet) in bullets.iter().enumerate() {
+        if bullet.is_alive {
+            cells.entry(cell(bullet.pos)).or_default().push(i);
+        }
+    }
+
+    for i in 0..bullets.len() {
+        let (cx, cy) = cell(bullets[i].pos);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let others = match cells.get(&(cx + dx, cy + dy)) {
+                    Some(others) => others,
+                    None => continue,
+                };
+                for &j in others {
+                    if j <= i || !bullets[i].is_alive {
+                        continue;
+                    }
+                    let (a, b) = (&bullets[i], &bullets[j]);
+                    if b.is_alive && a.team != b.team && crossed(a, b) {
+                        bullets[i].is_alive = false;
+                        bullets[j].is_alive = false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn cell(pos: Point2) -> (i32, i32) {
+    let x = (pos.x / CELL_SIZE).floor() as i32;
+    let y = (pos.y / CELL_SIZE).floor() as i32;
+    (x, y)
+}
+
+/// Whether two bullets came close enough to touch at any point during the
+/// last tick, so fast bullets can't fly through each other
+fn crossed(a: &Bullet, b: &Bullet) -> bool {
+    let end = a.pos - b.pos;
+    let moved = crate::DT * (a.vel - b.vel);
+    let start = end - moved;
+    let length = moved.norm_squared();
+    let t = if length > 0.0 {
+        math::clamp(0.0, 1.0, -start.dot(&moved) / length)
+    } else {
+        0.0
+    };
+    let closest = start + t * moved;
+    closest.x.abs() < BULLET_WIDTH && closest.y.abs() < BULLET_HEIGHT
 }
@@ -0,0 +1,119 @@
+use std::io;
+
+use crate::player::ControlState;
+
+// Little endian helpers shared by the binary file and packet formats
+
+pub fn put_u8(out: &mut Vec<u8>, value: u8) {
+    out.push(value);
+}
+
+pub fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_f32(out: &mut Vec<u8>, value: f32) {
+    put_u32(out, value.to_bits());
+}
+
+pub fn put_control_state(out: &mut Vec<u8>, state: &ControlState) {
+    let flags = state.jump as u8
+        | (state.shoot as u8) << 1
+        | (state.l_pressed as u8) << 2
+        | (state.r_pressed as u8) << 3
+        | (state.repair as u8) << 4
+        | (state.u_pressed as u8) << 5
+        | (state.d_pressed as u8) << 6
+        | (state.dash as u8) << 7;
+    put_u8(out, flags);
+    put_f32(out, state.lr);
+    put_f32(out, state.facing);
+    put_f32(out, state.aim_x);
+    put_f32(out, state.aim_y);
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Unexpected end of data",
+            ));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    /// A player slot index
+    pub fn slot(&mut self) -> io::Result<usize> {
+        let slot = self.u8()? as usize;
+        if slot >= 4 {
+            return Err(invalid_data(format!("Invalid player slot {}", slot)));
+        }
+        Ok(slot)
+    }
+
+    pub fn control_state(&mut self) -> io::Result<ControlState> {
+        let flags = self.u8()?;
+        Ok(ControlState {
+            jump: flags & 1 != 0,
+            shoot: flags & (1 << 1) != 0,
+            l_pressed: flags & (1 << 2) != 0,
+            r_pressed: flags & (1 << 3) != 0,
+            repair: flags & (1 << 4) != 0,
+            u_pressed: flags & (1 << 5) != 0,
+            d_pressed: flags & (1 << 6) != 0,
+            dash: flags & (1 << 7) != 0,
+            lr: self.f32()?,
+            facing: self.f32()?,
+            aim_x: self.f32()?,
+            aim_y: self.f32()?,
+            ..ControlState::default()
+        })
+    }
+}
+
+pub fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
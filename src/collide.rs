@@ -1,4 +1,4 @@
-use crate::grid::Grid;
+use crate::grid::{Grid, Tile};
 use ggez::graphics::{Rect, Vector2};
 
 use crate::math;
@@ -6,21 +6,139 @@ use crate::math;
 pub type WorldRect = Rect;
 pub const COLLISION_TOLERANCE: f32 = 0.01;
 
-pub fn get_overlapping_tiles(grids: &[Grid], rect: Rect, out: &mut Vec<(WorldRect, Vector2)>) {
+/// How a collider pushes things out of it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColliderKind {
+    Solid,
+    /// Only stops things landing on it from above, anything else passes through
+    OneWay,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Collider {
+    pub rect: WorldRect,
+    /// The velocity of the grid the collider is in
+    pub vel: Vector2,
+    pub kind: ColliderKind,
+    /// The tile the collider came from
+    pub tile: Tile,
+}
+
+/// Where a moving rectangle first touched something
+#[derive(Debug, Copy, Clone)]
+pub struct Hit {
+    /// How far along the move the contact happened, from 0 to 1
+    pub time: f32,
+    /// Points out of the side that was hit
+    pub normal: Vector2,
+}
+
+/// Swept AABB test of a rectangle moving by `motion` against a fixed one.
+/// Rectangles that already overlap at the start don't count as hitting, the
+/// sweep is only there to catch what a move would skip over. Overlaps get
+/// handled by the callers: `resolve_colliders_vert` and `_horiz` push
+/// players back out, and bullets count them as a hit straight away.
+pub fn sweep(rect: Rect, motion: Vector2, fixed: Rect) -> Option<Hit> {
+    // Shrink the moving rectangle to a point by growing the fixed one
+    let left = fixed.x - rect.w;
+    let right = fixed.x + fixed.w;
+    let bottom = fixed.y - rect.h;
+    let top = fixed.y + fixed.h;
+
+    let (entry_x, exit_x) = slab(rect.x, motion.x, left, right)?;
+    let (entry_y, exit_y) = slab(rect.y, motion.y, bottom, top)?;
+    let entry = entry_x.max(entry_y);
+    let exit = exit_x.min(exit_y);
+    if entry >= exit || !(0.0..=1.0).contains(&entry) {
+        return None;
+    }
+
+    let normal = if entry_x > entry_y {
+        Vector2::new(-motion.x.signum(), 0.0)
+    } else {
+        Vector2::new(0.0, -motion.y.signum())
+    };
+    Some(Hit {
+        time: entry,
+        normal,
+    })
+}
+
+/// The times a point moving along one axis enters and leaves a range
+fn slab(start: f32, motion: f32, min: f32, max: f32) -> Option<(f32, f32)> {
+    if motion == 0.0 {
+        if start <= min || start >= max {
+            return None;
+        }
+        return Some((f32::NEG_INFINITY, f32::INFINITY));
+    }
+    let a = (min - start) / motion;
+    let b = (max - start) / motion;
+    Some((a.min(b), a.max(b)))
+}
+
+/// The first tile a rectangle moving with `velocity` for one tick would
+/// run into, taking the movement of each grid into account. One way tiles
+/// only count when they're landed on from above.
+pub fn sweep_tiles(grids: &[Grid], rect: Rect, velocity: Vector2) -> Option<(Hit, Collider)> {
+    let mut tiles = Vec::new();
+    let mut first: Option<(Hit, Collider)> = None;
+    for grid in grids {
+        let motion = crate::DT * (velocity - grid.vel);
+        tiles.clear();
+        grid.overlapping_tiles(math::swept_rect(rect, motion), &mut tiles);
+        for &tile in &tiles {
+            let (fixed, kind) = grid.to_world_collider(tile);
+            if kind == ColliderKind::OneWay {
+                let from_above = rect.y >= fixed.y + fixed.h - COLLISION_TOLERANCE;
+                if motion.y >= 0.0 || !from_above {
+                    continue;
+                }
+            }
+            let hit = match sweep(rect, motion, fixed) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            if first.is_none_or(|(first, _)| hit.time < first.time) {
+                let collider = Collider {
+                    rect: fixed,
+                    vel: grid.vel,
+                    kind,
+                    tile: tile.0,
+                };
+                first = Some((hit, collider));
+            }
+        }
+    }
+    first
+}
+
+pub fn get_overlapping_tiles(grids: &[Grid], rect: Rect, out: &mut Vec<Collider>) {
     let mut tiles = Vec::with_capacity(6);
     for grid in grids {
         tiles.clear();
         grid.overlapping_tiles(rect, &mut tiles);
         for &tile in &tiles {
-            out.push((grid.to_world_collider(tile), grid.vel));
+            let (rect, kind) = grid.to_world_collider(tile);
+            out.push(Collider {
+                rect,
+                vel: grid.vel,
+                kind,
+                tile: tile.0,
+            });
         }
     }
 }
 
 /// Give the horizontal displacement and velocity of a moving rectangle intersecting another rectangle
 /// Assumes that the origin of the rectanges are at the lower left corner.
-pub fn resolve_collider_horiz(rect: Rect, velocity: Vector2, fixed: Rect) -> (f32, Vector2) {
-    if rect.overlaps(&fixed) {
+pub fn resolve_collider_horiz(
+    rect: Rect,
+    velocity: Vector2,
+    fixed: Rect,
+    kind: ColliderKind,
+) -> (f32, Vector2) {
+    if kind == ColliderKind::Solid && rect.overlaps(&fixed) {
         // Intersects while moving left, so push out right
         if velocity.x < 0.0 {
             return (
@@ -39,7 +157,21 @@ pub fn resolve_collider_horiz(rect: Rect, velocity: Vector2, fixed: Rect) -> (f3
 
 /// Give the vertical displacement and resulting velocity of a moving rectangle intersecting another rectangle
 /// Assumes that the origin of the rectanges are at the lower left corner.
-pub fn resolve_collider_vert(rect: Rect, velocity: Vector2, fixed: Rect) -> (f32, Vector2) {
+/// One way colliders only push out rectangles that are moving down, and were
+/// above the collider before they moved.
+pub fn resolve_collider_vert(
+    rect: Rect,
+    velocity: Vector2,
+    fixed: Rect,
+    kind: ColliderKind,
+) -> (f32, Vector2) {
+    if kind == ColliderKind::OneWay {
+        let previous_bottom = rect.y - velocity.y * crate::DT;
+        let from_above = previous_bottom >= fixed.y + fixed.h - COLLISION_TOLERANCE;
+        if velocity.y > 0.0 || !from_above {
+            return (0.0, velocity);
+        }
+    }
     if rect.overlaps(&fixed) {
         // Intersects while moving up, so push out down
         if velocity.y > 0.0 {
@@ -57,25 +189,72 @@ pub fn resolve_collider_vert(rect: Rect, velocity: Vector2, fixed: Rect) -> (f32
     (0.0, velocity)
 }
 
+/// Also gives the tile of the last collider that pushed the rectangle, which
+/// is the one being stood on after vertical resolution pushes up.
 macro_rules! resolve_colliders {
     ($fname:ident, $worker:ident, $dim:ident) => {
         pub fn $fname(
             rect: Rect,
             velocity: Vector2,
-            colliders: &[(Rect, Vector2)],
-        ) -> (f32, Vector2) {
+            colliders: &[Collider],
+        ) -> (f32, Vector2, Option<Tile>) {
             let mut net_disp = Vector2::new(0.0, 0.0);
             let mut net_vel = velocity;
-            for (collider, vel) in colliders {
+            let mut contact = None;
+            for collider in colliders {
                 let disp_rect = math::rect_from_point(rect.point() + net_disp, rect.w, rect.h);
-                let (res_disp, res_vel) = $worker(disp_rect, net_vel - vel, *collider);
+                let (res_disp, res_vel) = $worker(
+                    disp_rect,
+                    net_vel - collider.vel,
+                    collider.rect,
+                    collider.kind,
+                );
+                if res_disp != 0.0 {
+                    contact = Some(collider.tile);
+                }
                 net_disp.$dim += res_disp;
-                net_vel = res_vel + vel;
+                net_vel = res_vel + collider.vel;
             }
-            (net_disp.$dim, net_vel)
+            (net_disp.$dim, net_vel, contact)
         }
     };
 }
 
 resolve_colliders!(resolve_colliders_horiz, resolve_collider_horiz, x);
 resolve_colliders!(resolve_colliders_vert, resolve_collider_vert, y);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fast_rects_stop_at_the_first_side_they_reach() {
+        let rect = Rect::new(0.0, 5.0, 1.0, 1.0);
+        let fixed = Rect::new(0.5, 0.0, 1.0, 1.0);
+        let hit = sweep(rect, Vector2::new(0.0, -10.0), fixed).unwrap();
+        assert_eq!(hit.time, 0.4);
+        assert_eq!(hit.normal, Vector2::new(0.0, 1.0));
+
+        assert!(sweep(rect, Vector2::new(0.0, -3.0), fixed).is_none());
+        assert!(sweep(rect, Vector2::new(5.0, -10.0), fixed).is_none());
+    }
+
+    #[test]
+    fn embedded_rects_are_pushed_out_by_resolution() {
+        let rect = Rect::new(0.0, 0.5, 1.0, 1.0);
+        let fixed = Rect::new(0.0, 0.0, 1.0, 1.0);
+        let velocity = Vector2::new(0.0, -30.0);
+        assert!(sweep(rect, crate::DT * velocity, fixed).is_none());
+
+        let collider = Collider {
+            rect: fixed,
+            vel: Vector2::new(0.0, 0.0),
+            kind: ColliderKind::Solid,
+            tile: Tile::Bedrock,
+        };
+        let (disp, vel, tile) = resolve_colliders_vert(rect, velocity, &[collider]);
+        assert_eq!(rect.y + disp, fixed.y + fixed.h + COLLISION_TOLERANCE);
+        assert_eq!(vel, Vector2::new(0.0, 0.0));
+        assert_eq!(tile, Some(Tile::Bedrock));
+    }
+}
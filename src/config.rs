@@ -1,11 +1,26 @@
-use std::io::Read;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::Read,
+};
 
 use ggez::{Context, GameResult};
 
 use crate::draw;
+use crate::generator::Style;
+use crate::pickup::PickupChance;
+use crate::weapon::WeaponDef;
 
 const CONFIG_FILE: &str = "/config.toml";
 
+#[derive(Deserialize)]
+pub struct HazardInfo {
+    /// Whether bullets can break it
+    pub destructible: bool,
+    /// How fast a player gets thrown away from it
+    pub knockback: f32,
+}
+
 #[derive(Deserialize)]
 pub struct MenuInfo {
     pub join_pos: (f32, f32),
@@ -55,16 +70,40 @@ macro_rules! config {
             let mut text = String::new();
             file.read_to_string(&mut text)?;
 
-            let config: Config = toml::from_str(&text).map_err(
+            load_str(&text).map_err(
                 |err| format!("Error loading '{}' {}", CONFIG_FILE, err)
             )?;
+            Ok(())
+        }
+
+        /// Load the config from the contents of a config file, for tools
+        /// that run without a ggez context
+        pub fn load_str(text: &str) -> Result<(), toml::de::Error> {
+            let config: Config = toml::from_str(text)?;
             $(unsafe { $NAME::$NAME = config.$fieldname; };)*
             Ok(())
         }
     }
 }
 
+/// A hash of the config file and the other given files, so replays and
+/// network games can tell when they aren't playing by the same rules
+pub fn fingerprint(ctx: &mut Context, files: &[&str]) -> GameResult<u64> {
+    let mut hasher = DefaultHasher::new();
+    for path in std::iter::once(&CONFIG_FILE).chain(files) {
+        let mut text = String::new();
+        ctx.filesystem.open(path)?.read_to_string(&mut text)?;
+        text.hash(&mut hasher);
+    }
+    Ok(hasher.finish())
+}
+
 config! {
+    config GAME: game = Game {
+        seed: Option<u64> = None,
+        mode: String = String::new(),
+    };
+
     config TEAM: team = Team {
         colors: [[f32; 4]; 4] = [
             [0.25, 0.7, 1.0, 1.0],
@@ -83,17 +122,100 @@ config! {
         shoot_offset_y: f32 = 0.8 / 1.5,
         jump_power: f32 = 16.0,
         second_jump_power: f32 = 16.0,
-
+        invulnerable_time: f32 = 1.0,
+        hit_invulnerable_time: f32 = 0.2,
+        hit_stun: f32 = 0.3,
+        hit_stun_control: f32 = 0.25,
+        wall_slide_speed: f32 = 3.0,
+        wall_jump_push: f32 = 12.0,
+        dash_speed: f32 = 30.0,
+        dash_time: f32 = 0.12,
+        dash_cooldown: f32 = 0.8,
+        dash_invulnerable_time: f32 = 0.15,
+        coyote_ticks: u8 = 6,
+        jump_buffer_ticks: u8 = 6,
+        jump_release_cut: f32 = 0.5,
     };
 
     config GRID: grid = Grid {
         tile_max_health: u8 = 4,
+        reinforced_max_health: u8 = 8,
+        regen_seconds: f32 = 2.0,
+        repair_amount: u32 = 6,
+        repair_cooldown: f32 = 1.0,
+        repair_window: f32 = 2.0,
         falling_accel: f32 = -25.0,
         death_threshold: f32 = 0.95,
         no_spawn_threshold: f32 = 0.5,
         gap: f32 = 0.5,
     };
 
+    config SURFACES: surfaces = Surfaces {
+        ice_friction: f32 = 0.99,
+        ice_control: f32 = 0.2,
+        bounce_impulse: f32 = 24.0,
+        conveyor_push: f32 = 0.2,
+    };
+
+    config EXPLOSIVES: explosives = Explosives {
+        health: u8 = 2,
+        radius: f32 = 2.5,
+        tile_damage: u8 = 3,
+        knockback: f32 = 18.0,
+    };
+
+    config WEAPONS: weapons = Weapons {
+        default: String = String::new(),
+        list: Vec<WeaponDef> = Vec::new(),
+    };
+
+    config PICKUPS: pickups = Pickups {
+        spawn_rate: f32 = 0.2,
+        max_pickups: usize = 3,
+        rapid_fire_seconds: f32 = 8.0,
+        rapid_fire_cooldown: f32 = 0.1,
+        shield_seconds: f32 = 6.0,
+        table: Vec<PickupChance> = Vec::new(),
+    };
+
+    config HAZARDS: hazards = Hazards {
+        spikes: HazardInfo = HazardInfo {
+            destructible: true,
+            knockback: 12.0,
+        },
+        lava: HazardInfo = HazardInfo {
+            destructible: false,
+            knockback: 16.0,
+        },
+        electric: HazardInfo = HazardInfo {
+            destructible: true,
+            knockback: 8.0,
+        },
+    };
+
+    config MODULES: modules = Modules {
+        grids_per_tier: u32 = 3,
+        seconds_per_tier: f32 = 45.0,
+        tier_falloff: f32 = 0.5,
+    };
+
+    config GENERATOR: generator = Generator {
+        enabled: bool = false,
+        count: u32 = 6,
+        tiers: u32 = 3,
+        weight: f32 = 1.0,
+        easy: Style = Style {
+            density: 0.6,
+            gap_width: 2,
+            walls: 2,
+        },
+        hard: Style = Style {
+            density: 0.2,
+            gap_width: 5,
+            walls: 0,
+        },
+    };
+
     config MENU: menu = Menu {
         life_offset: (f32, f32) = (1.2, 0.0),
         heart_offset: (f32, f32) = (0.7, 0.0),
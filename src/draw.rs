@@ -73,6 +73,11 @@ pub fn draw_ex(ctx: &mut Context, drawable: &Drawable, param: DrawParam) -> Game
     graphics::draw_ex(ctx, drawable, param)
 }
 
+/// Convert a position in the window, like the mouse, to world coordinates
+pub fn screen_to_world(x: f32, y: f32) -> Point2 {
+    Point2::new(x / SCALE_X, (SCREEN_HEIGHT - y) / SCALE_Y)
+}
+
 pub fn draw(ctx: &mut Context, drawable: &Drawable, dest: Point2, rotation: f32) -> GameResult<()> {
     let param = DrawParam {
         dest,
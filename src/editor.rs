@@ -0,0 +1,375 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ggez::{
+    event::{self, Keycode, Mod},
+    graphics::{self, Color, DrawMode, Point2},
+    Context, GameResult,
+};
+
+use crate::bullet::Bullet;
+use crate::draw;
+use crate::explosion;
+use crate::grid::{self, Grid, GridCoord, ModuleDef, Tile, GRID_HEIGHT, GRID_WIDTH};
+use crate::images::Images;
+use crate::player::{ControlState, Player, Team};
+use crate::simulation::{self, Event, GameRng};
+
+/// Where the module being edited sits on the screen
+const GRID_Y: f32 = GRID_HEIGHT as f32;
+
+/// Lets designers paint modules and try them out with a live player.
+///
+/// Left click paints the brush, right click erases, or move the cursor with
+/// the arrow keys and paint with space. Tab picks the next brush, page up and
+/// down switch modules, insert adds one, P starts and stops a playtest,
+/// ctrl+S saves and escape leaves.
+pub struct Editor {
+    modules: Vec<ModuleDef>,
+    index: usize,
+    path: PathBuf,
+    dirty: bool,
+    cursor: (GridCoord, GridCoord),
+    brush: usize,
+    playtest: Option<Playtest>,
+    keys: Keys,
+    rng: GameRng,
+}
+
+struct Playtest {
+    grid: Grid,
+    player: Option<Player>,
+    bullets: Vec<Bullet>,
+    events: Vec<Event>,
+}
+
+#[derive(Default)]
+struct Keys {
+    left: bool,
+    right: bool,
+    up: bool,
+    down: bool,
+    shoot: bool,
+    repair: bool,
+    dash: bool,
+    facing: f32,
+}
+
+impl Editor {
+    pub fn new(modules: Vec<ModuleDef>, path: &Path, seed: u64) -> Editor {
+        // Generated modules get made again every match, they don't belong in the file
+        let mut modules: Vec<_> = modules
+            .into_iter()
+            .filter(|x| !x.has_tag("generated"))
+            .collect();
+        // Always have something to edit
+        if modules.is_empty() {
+            modules.push(blank_module(1));
+        }
+        let editor = Editor {
+            modules,
+            index: 0,
+            path: path.to_path_buf(),
+            dirty: false,
+            cursor: (GRID_WIDTH / 2, GRID_HEIGHT / 2),
+            brush: 0,
+            playtest: None,
+            keys: Keys {
+                facing: 1.0,
+                ..Keys::default()
+            },
+            rng: simulation::new_rng(seed),
+        };
+        editor.print_status();
+        editor
+    }
+
+    /// The modules as they are now, saved or not
+    pub fn modules(&self) -> &[ModuleDef] {
+        &self.modules
+    }
+
+    fn print_status(&self) {
+        let module = &self.modules[self.index];
+        println!(
+            "Editing '{}' ({} of {}), brush '{}'{}",
+            module.name,
+            self.index + 1,
+            self.modules.len(),
+            Tile::CHARS[self.brush],
+            if self.dirty { ", unsaved" } else { "" }
+        );
+    }
+
+    fn paint(&mut self, (x, y): (GridCoord, GridCoord), tile: Tile) {
+        let tiles = &mut self.modules[self.index].tiles;
+        if tiles[y][x] != tile {
+            tiles[y][x] = tile;
+            self.dirty = true;
+        }
+    }
+
+    fn brush_tile(&self) -> Tile {
+        Tile::from_char(Tile::CHARS[self.brush]).unwrap()
+    }
+
+    /// Returns false once the editor should be closed
+    pub fn key(&mut self, keycode: Keycode, keymod: Mod, pressed: bool) -> bool {
+        if self.playtest.is_some() {
+            return self.playtest_key(keycode, pressed);
+        }
+        if !pressed {
+            return true;
+        }
+
+        let (x, y) = self.cursor;
+        match keycode {
+            Keycode::Escape => {
+                if self.dirty {
+                    println!(
+                        "Leaving the editor without saving, the changes stay until the game closes"
+                    );
+                }
+                return false;
+            }
+            Keycode::S if keymod.intersects(event::LCTRLMOD | event::RCTRLMOD) => self.save(),
+            Keycode::Left => self.cursor.0 = x.saturating_sub(1),
+            Keycode::Right => self.cursor.0 = (x + 1).min(GRID_WIDTH - 1),
+            Keycode::Down => self.cursor.1 = y.saturating_sub(1),
+            Keycode::Up => self.cursor.1 = (y + 1).min(GRID_HEIGHT - 1),
+            Keycode::Space => {
+                let tile = self.brush_tile();
+                self.paint(self.cursor, tile);
+            }
+            Keycode::Delete | Keycode::Backspace => self.paint(self.cursor, Tile::Air),
+            Keycode::Tab => {
+                self.brush = (self.brush + 1) % Tile::CHARS.len();
+                self.print_status();
+            }
+            Keycode::PageUp => {
+                self.index = (self.index + self.modules.len() - 1) % self.modules.len();
+                self.print_status();
+            }
+            Keycode::PageDown => {
+                self.index = (self.index + 1) % self.modules.len();
+                self.print_status();
+            }
+            Keycode::Insert => {
+                let module = blank_module(self.modules.len() + 1);
+                self.index += 1;
+                self.modules.insert(self.index, module);
+                self.dirty = true;
+                self.print_status();
+            }
+            Keycode::P => self.start_playtest(),
+            _ => (),
+        }
+        true
+    }
+
+    fn playtest_key(&mut self, keycode: Keycode, pressed: bool) -> bool {
+        match keycode {
+            Keycode::P | Keycode::Escape if pressed => {
+                self.playtest = None;
+                println!("Back to editing");
+            }
+            Keycode::A | Keycode::Left => {
+                self.keys.left = pressed;
+                if pressed {
+                    self.keys.facing = -1.0;
+                }
+            }
+            Keycode::D | Keycode::Right => {
+                self.keys.right = pressed;
+                if pressed {
+                    self.keys.facing = 1.0;
+                }
+            }
+            Keycode::W | Keycode::Up => self.keys.up = pressed,
+            Keycode::S | Keycode::Down => self.keys.down = pressed,
+            Keycode::Space | Keycode::Tab | Keycode::Comma => self.keys.shoot = pressed,
+            Keycode::Q | Keycode::Period => self.keys.repair = pressed,
+            Keycode::LShift | Keycode::Slash => self.keys.dash = pressed,
+            _ => (),
+        }
+        true
+    }
+
+    /// Paint with the left button and erase with the right one
+    pub fn mouse(&mut self, x: i32, y: i32, left: bool, right: bool) {
+        if self.playtest.is_some() {
+            return;
+        }
+        let point = draw::screen_to_world(x as f32, y as f32);
+        let (x, y) = (point.x.floor(), (point.y - GRID_Y).floor());
+        if x < 0.0 || y < 0.0 || x >= GRID_WIDTH as f32 || y >= GRID_HEIGHT as f32 {
+            return;
+        }
+        self.cursor = (x as GridCoord, y as GridCoord);
+        if left {
+            let tile = self.brush_tile();
+            self.paint(self.cursor, tile);
+        } else if right {
+            self.paint(self.cursor, Tile::Air);
+        }
+    }
+
+    fn save(&mut self) {
+        match fs::write(&self.path, grid::write_modules(&self.modules)) {
+            Ok(()) => {
+                self.dirty = false;
+                println!(
+                    "Saved {} modules to '{}'",
+                    self.modules.len(),
+                    self.path.display()
+                );
+            }
+            Err(err) => println!("Could not save '{}': {}", self.path.display(), err),
+        }
+    }
+
+    fn start_playtest(&mut self) {
+        let mut rng = self.rng.clone();
+        if grid::find_spawn_location(self.modules[self.index].tiles, &mut rng).is_none() {
+            println!("There's nowhere to spawn in this module");
+            return;
+        }
+        let grid = Grid::new_from_module(GRID_Y, self.modules[self.index].tiles);
+        self.keys = Keys {
+            facing: 1.0,
+            ..Keys::default()
+        };
+        self.playtest = Some(Playtest {
+            grid,
+            player: None,
+            bullets: Vec::new(),
+            events: Vec::new(),
+        });
+        println!("Playtesting, press P to go back to editing");
+    }
+
+    /// Advance the playtest by one tick, returning what happened in it
+    pub fn tick(&mut self) -> &[Event] {
+        let test = match &mut self.playtest {
+            Some(test) => test,
+            None => return &[],
+        };
+        test.events.clear();
+
+        // Put the player back whenever they die or fall out of the module
+        let lost = match &test.player {
+            Some(player) => !player.alive || player.pos().y < GRID_Y - 2.0,
+            None => true,
+        };
+        if lost {
+            let mut player = Player::new(Team(0));
+            let spawned = player.respawn(&test.grid, &mut self.rng);
+            test.player = if spawned { Some(player) } else { None };
+        }
+
+        let keys = &self.keys;
+        let mut lr = 0.0;
+        if keys.left {
+            lr -= 1.0;
+        }
+        if keys.right {
+            lr += 1.0;
+        }
+        let mut aim_y = 0.0;
+        if keys.down {
+            aim_y -= 1.0;
+        }
+        if keys.up {
+            aim_y += 1.0;
+        }
+        let mut players = [test.player.take()];
+        if let Some(player) = &mut players[0] {
+            player.control_state = ControlState {
+                lr,
+                jump: keys.up,
+                shoot: keys.shoot,
+                repair: keys.repair,
+                dash: keys.dash,
+                l_pressed: keys.left,
+                r_pressed: keys.right,
+                u_pressed: keys.up,
+                d_pressed: keys.down,
+                facing: keys.facing,
+                aim_x: lr,
+                aim_y,
+                ..ControlState::default()
+            };
+            player.update(&mut test.bullets, &mut test.events);
+            player.fixed_update(std::slice::from_ref(&test.grid), &mut test.events);
+        }
+        for bullet in &mut test.bullets {
+            bullet.fixed_update(
+                &mut test.events,
+                std::slice::from_mut(&mut test.grid),
+                &mut players,
+                false,
+            );
+        }
+        simulation::repair_grids(
+            &mut players,
+            std::slice::from_mut(&mut test.grid),
+            &mut test.events,
+        );
+        test.bullets.retain(|bullet| bullet.is_alive);
+        explosion::resolve(
+            std::slice::from_mut(&mut test.grid),
+            &mut players,
+            &mut test.events,
+        );
+        // Only for regenerating tiles, nothing happens to dead grids here
+        test.grid.update(false);
+        test.player = players[0].take();
+
+        &test.events
+    }
+
+    pub fn draw(&mut self, ctx: &mut Context, images: &Images) -> GameResult<()> {
+        if let Some(test) = &mut self.playtest {
+            test.grid.draw(ctx, images)?;
+            if let Some(player) = &test.player {
+                player.draw(ctx, images)?;
+            }
+            for bullet in &test.bullets {
+                bullet.draw(ctx, images)?;
+            }
+            return Ok(());
+        }
+
+        let mut grid = Grid::new_from_module(GRID_Y, self.modules[self.index].tiles);
+        grid.draw(ctx, images)?;
+
+        // Outline the module, and the tile under the cursor
+        let (width, height) = (GRID_WIDTH as f32, GRID_HEIGHT as f32);
+        draw_outline(ctx, Point2::new(0.0, GRID_Y), width, height, 0.5)?;
+        let (x, y) = (self.cursor.0 as f32, self.cursor.1 as f32);
+        draw_outline(ctx, Point2::new(x, GRID_Y + y), 1.0, 1.0, 1.0)
+    }
+}
+
+/// A module with just a floor, named after its position in the file
+fn blank_module(number: usize) -> ModuleDef {
+    let mut tiles = [[Tile::Air; GRID_WIDTH]; GRID_HEIGHT];
+    tiles[0] = [Tile::from_char('#').unwrap(); GRID_WIDTH];
+    ModuleDef::new(format!("#{}", number), tiles)
+}
+
+fn draw_outline(ctx: &mut Context, pos: Point2, w: f32, h: f32, alpha: f32) -> GameResult<()> {
+    let points = [
+        Point2::new(0.0, 0.0),
+        Point2::new(0.0, h),
+        Point2::new(w, h),
+        Point2::new(w, 0.0),
+    ];
+    let mesh = graphics::Mesh::new_polygon(ctx, DrawMode::Line(0.05), &points)?;
+    graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, alpha))?;
+    let result = draw::draw(ctx, &mesh, pos, 0.0);
+    graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+    result
+}
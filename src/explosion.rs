@@ -0,0 +1,60 @@
+use ggez::graphics::{Point2, Vector2};
+
+use crate::config::EXPLOSIVES;
+use crate::grid::{Grid, GRID_HEIGHT, GRID_WIDTH, TILE_SIZE};
+use crate::player::Player;
+use crate::simulation::Event;
+use crate::sound::SoundEffect;
+
+/// Set off every explosive tile destroyed since the last call, along with the
+/// ones those blow up in turn. Blasts reach into every grid, not just the one
+/// the explosive was in.
+pub fn resolve(grids: &mut [Grid], players: &mut [Option<Player>], events: &mut Vec<Event>) {
+    let mut pending: Vec<Point2> = Vec::new();
+    for grid in grids.iter_mut() {
+        pending.extend(grid.take_explosions());
+    }
+
+    while let Some(center) = pending.pop() {
+        events.push(Event::Sound(SoundEffect::BreakBlock));
+        for grid in grids.iter_mut() {
+            damage_tiles(grid, center);
+            pending.extend(grid.take_explosions());
+        }
+
+        for player in players.iter_mut().flatten() {
+            let away = player.center() - center;
+            let distance = away.norm();
+            if distance > EXPLOSIVES.radius {
+                continue;
+            }
+            let dir = if distance > 0.0 {
+                away / distance
+            } else {
+                Vector2::new(0.0, 1.0)
+            };
+            player.hurt(dir * EXPLOSIVES.knockback, events);
+        }
+    }
+}
+
+fn damage_tiles(grid: &mut Grid, center: Point2) {
+    let radius = EXPLOSIVES.radius;
+    let bottom = grid.world_offset.y;
+    if center.y + radius < bottom || center.y - radius > bottom + GRID_HEIGHT as f32 {
+        return;
+    }
+
+    let half_tile = Vector2::new(TILE_SIZE / 2.0, TILE_SIZE / 2.0);
+    for y in 0..GRID_HEIGHT {
+        for x in 0..GRID_WIDTH {
+            let tile_center = grid.to_world_coords((x, y)) + half_tile;
+            if (tile_center - center).norm() > radius {
+                continue;
+            }
+            for _ in 0..EXPLOSIVES.tile_damage {
+                grid.damage_tile(x, y);
+            }
+        }
+    }
+}
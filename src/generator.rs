@@ -0,0 +1,145 @@
+use rand::Rng;
+
+use crate::config::{GENERATOR, GRID};
+use crate::grid::{self, Module, ModuleDef, Tile, GRID_HEIGHT, GRID_WIDTH};
+use crate::reach;
+use crate::simulation::{self, GameRng};
+
+/// How many layouts to try before settling for a plain floor
+const MAX_ATTEMPTS: u32 = 100;
+/// The rows that get platforms. Every row gets gaps, the bottom one too, and
+/// `is_playable` uses the reach check to throw out layouts that can't be crossed.
+const PLATFORM_ROWS: [usize; 3] = [0, 3, 6];
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+pub struct Style {
+    /// Roughly how much of every platform row is solid, from 0 to 1
+    pub density: f32,
+    /// The widest gap between two platforms
+    pub gap_width: usize,
+    /// How many walls stick up out of the platforms
+    pub walls: usize,
+}
+
+impl Style {
+    fn lerp(a: &Style, b: &Style, t: f32) -> Style {
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        Style {
+            density: lerp(a.density, b.density),
+            gap_width: lerp(a.gap_width as f32, b.gap_width as f32).round() as usize,
+            walls: lerp(a.walls as f32, b.walls as f32).round() as usize,
+        }
+    }
+}
+
+/// The generated modules that get mixed in with the hand made ones. Their
+/// tiers go from the easy style up to the hard one.
+pub fn generate_pool(seed: u64) -> Vec<ModuleDef> {
+    // Kept apart from the gameplay rng, so turning the generator on doesn't
+    // change anything else about a seed
+    let mut rng = simulation::new_rng(seed ^ 0x6765_6e65_7261_7465);
+    let tiers = GENERATOR.tiers.max(1);
+    (0..GENERATOR.count)
+        .map(|i| {
+            let tier = i * tiers / GENERATOR.count;
+            let t = if tiers > 1 {
+                tier as f32 / (tiers - 1) as f32
+            } else {
+                0.0
+            };
+            let style = Style::lerp(&GENERATOR.easy, &GENERATOR.hard, t);
+            let mut module =
+                ModuleDef::new(format!("generated #{}", i + 1), generate(&style, &mut rng));
+            module.weight = GENERATOR.weight;
+            module.tier = tier;
+            module.tags.push("generated".into());
+            module
+        })
+        .collect()
+}
+
+/// Build a module in the given style that players can spawn in and get
+/// around with their jumps
+pub fn generate(style: &Style, rng: &mut GameRng) -> Module {
+    if let Some(module) = try_generate(style, rng) {
+        return module;
+    }
+    println!(
+        "No playable layout in {} attempts for {:?}, using a plain floor",
+        MAX_ATTEMPTS, style
+    );
+    let mut module = [[Tile::Air; GRID_WIDTH]; GRID_HEIGHT];
+    module[0] = [Tile::Solid(GRID.tile_max_health); GRID_WIDTH];
+    module
+}
+
+fn try_generate(style: &Style, rng: &mut GameRng) -> Option<Module> {
+    for _ in 0..MAX_ATTEMPTS {
+        let module = layout(style, rng);
+        if is_playable(&module, rng) {
+            return Some(module);
+        }
+    }
+    None
+}
+
+pub fn is_playable(module: &Module, rng: &mut impl Rng) -> bool {
+    grid::find_spawn_location(*module, rng).is_some() && reach::unreachable_spots(module).is_empty()
+}
+
+fn layout(style: &Style, rng: &mut GameRng) -> Module {
+    let solid = Tile::Solid(GRID.tile_max_health);
+    let mut module = [[Tile::Air; GRID_WIDTH]; GRID_HEIGHT];
+    let gap_width = style.gap_width.max(1);
+    let longest = 3 + (style.density.max(0.0) * 12.0) as usize;
+
+    for &y in &PLATFORM_ROWS {
+        let mut x = rng.gen_range(0, gap_width + 1);
+        while x < GRID_WIDTH {
+            let len = rng.gen_range(3, longest + 1);
+            for tile in module[y].iter_mut().skip(x).take(len) {
+                *tile = solid;
+            }
+            x += len + rng.gen_range(gap_width.div_ceil(2), gap_width + 1);
+        }
+    }
+
+    for _ in 0..style.walls {
+        let x = rng.gen_range(1, GRID_WIDTH - 1);
+        let base = *rng.choose(&PLATFORM_ROWS).unwrap();
+        let height = rng.gen_range(2, 4);
+        for row in &mut module[base..(base + height).min(GRID_HEIGHT)] {
+            row[x] = solid;
+        }
+    }
+
+    module
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::tests::load_config;
+
+    #[test]
+    fn every_style_makes_playable_modules() {
+        load_config();
+        for seed in 0..200 {
+            let mut rng = simulation::new_rng(seed);
+            for style in &[GENERATOR.easy, GENERATOR.hard] {
+                let module = try_generate(style, &mut rng)
+                    .unwrap_or_else(|| panic!("seed {} fell back to a floor", seed));
+                assert!(is_playable(&module, &mut rng), "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn the_same_seed_makes_the_same_modules() {
+        load_config();
+        let tiles =
+            |seed| -> Vec<Module> { generate_pool(seed).into_iter().map(|x| x.tiles).collect() };
+        assert_eq!(tiles(11), tiles(11));
+        assert_ne!(tiles(11), tiles(12));
+    }
+}
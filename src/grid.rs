@@ -1,20 +1,22 @@
 use std::{
-    io::{BufRead, BufReader},
+    hash::{Hash, Hasher},
+    io::Read,
     path,
 };
 
 use ggez::{
-    graphics::{DrawParam, Point2, Vector2},
+    graphics::{Color, DrawParam, Point2, Vector2},
     Context, GameResult,
 };
-use rand;
 use rand::Rng;
 
-use crate::collide::WorldRect;
-use crate::config::GRID;
+use crate::collide::{ColliderKind, WorldRect};
+use crate::config::{HazardInfo, EXPLOSIVES, GRID, HAZARDS};
 use crate::draw::{self, Batch, WorldCoord};
 use crate::math;
-use crate::sound::{Sound, SoundEffect};
+use crate::pickup::Pickup;
+use crate::simulation::Event;
+use crate::sound::SoundEffect;
 use crate::Images;
 
 pub type Module = [[Tile; GRID_WIDTH]; GRID_HEIGHT];
@@ -26,14 +28,25 @@ pub const TILE_SIZE: WorldCoord = 1.0f32;
 const GRID_TO_WORLD: f32 = TILE_SIZE as f32 * draw::WORLD_WIDTH / GRID_WIDTH as f32;
 
 /// A grid contains the collidable tiles that our dynamic objects interact with
+#[derive(Clone)]
 pub struct Grid {
     pub module: Module,       // Stored such that row zero is the bottom row
+    original: Module,         // The module as it was before any damage
     pub world_offset: Point2, // lower left corner
     pub state: GridState,
+    /// Died from losing too many tiles, rather than being dropped with the lobby
+    worn_out: bool,
+    /// Ticks left for players to repair a worn out grid before it gives way
+    crumbling: u32,
     total_tiles: usize, // Number of tiles alive at the start
     tiles_alive: usize, // Number of tiles still currently alive
     pub vel: Vector2,
     acc: Vector2,
+    /// Centres of the explosive tiles destroyed since `take_explosions`
+    explosions: Vec<Point2>,
+    ticks: u32,
+    /// Items waiting to be picked up, they fall along with the grid
+    pub pickups: Vec<Pickup>,
 }
 
 impl Grid {
@@ -41,12 +54,18 @@ impl Grid {
         let total_tiles = total_tiles(module);
         Grid {
             module: module,
+            original: module,
             world_offset: Point2::new(0.0, height),
             state: GridState::Alive,
+            worn_out: false,
+            crumbling: 0,
             total_tiles: total_tiles,
             tiles_alive: total_tiles,
             vel: Vector2::new(0.0, 0.0),
             acc: Vector2::new(0.0, 0.0),
+            explosions: Vec::new(),
+            ticks: 0,
+            pickups: Vec::new(),
         }
     }
 
@@ -54,7 +73,7 @@ impl Grid {
         self.world_offset.y
     }
 
-    pub fn fixed_update(&mut self, ctx: &mut Context, sounds: &mut Sound, goal_height: f32) {
+    pub fn fixed_update(&mut self, events: &mut Vec<Event>, goal_height: f32) {
         if self.world_offset.y > goal_height + GRID.gap {
             self.acc = Vector2::new(0.0, GRID.falling_accel);
         }
@@ -72,54 +91,137 @@ impl Grid {
             } else {
                 SoundEffect::GridLand
             };
-            sounds.play_sound(ctx, sound_effect);
+            events.push(Event::GridLanded {
+                height: goal_height,
+            });
+            events.push(Event::Sound(sound_effect));
         }
     }
 
-    pub fn update(&mut self) {
+    /// `repairable` is whether someone who could repair the grid is standing
+    /// on it, worn out grids only hold on for a while when there is
+    pub fn update(&mut self, repairable: bool) {
+        self.ticks = self.ticks.wrapping_add(1);
+        let regen_ticks = (GRID.regen_seconds / crate::DT).max(1.0) as u32;
+        if self.ticks.is_multiple_of(regen_ticks) {
+            for tile in self.module.iter_mut().flat_map(|row| row.iter_mut()) {
+                if let Tile::Regen(ref mut health) = tile {
+                    if *health > 0 && *health < GRID.tile_max_health {
+                        *health += 1;
+                    }
+                }
+            }
+        }
+
         if self.percent_tiles_alive() < GRID.death_threshold {
-            self.state = GridState::Dead;
+            if !self.worn_out {
+                self.worn_out = true;
+                // Hold on for a while so the players on it get a chance to
+                // repair it, grids nobody can save fall straight away
+                self.crumbling = if repairable {
+                    (GRID.repair_window / crate::DT) as u32
+                } else {
+                    0
+                };
+            }
+            if self.crumbling > 0 {
+                self.crumbling -= 1;
+            } else {
+                self.state = GridState::Dead;
+            }
+        } else if self.worn_out && self.vel.y == 0.0 {
+            // Repairs can still save a grid that hasn't started to fall
+            self.state = GridState::Alive;
+            self.worn_out = false;
         }
     }
 
-    pub fn draw(&mut self, ctx: &mut Context, images: &Images) -> GameResult<()> {
-        use self::Tile::*;
-        let mut batch = Batch::atlas(images.tiles.clone(), 16, 16);
-        for (j, row) in self.module.iter().enumerate() {
-            for (i, tile) in row.iter().enumerate() {
-                let dest = Point2::new(TILE_SIZE * i as f32, TILE_SIZE * j as f32);
-                match *tile {
-                    Air => continue,
-                    Tile::Start(idx) => batch.add(
-                        17 + idx as usize,
-                        DrawParam {
-                            dest,
-                            ..Default::default()
-                        },
-                    ),
-                    Tile::Leave => batch.add(
-                        16,
-                        DrawParam {
-                            dest,
-                            ..Default::default()
-                        },
-                    ),
-                    Solid(health) => {
-                        // Tile is dead, don't need to render
-                        if health == 0 {
+    /// Feed all of the grid's state into a checksum. Every field is listed so
+    /// new ones can't be left out by accident.
+    pub fn hash_state<H: Hasher>(&self, hasher: &mut H) {
+        let Grid {
+            module,
+            original,
+            world_offset,
+            state,
+            worn_out,
+            crumbling,
+            total_tiles,
+            tiles_alive,
+            vel,
+            acc,
+            explosions,
+            ticks,
+            pickups,
+        } = self;
+        (module, original, state, worn_out, crumbling, ticks).hash(hasher);
+        (total_tiles, tiles_alive).hash(hasher);
+        for value in &[world_offset.x, world_offset.y, vel.x, vel.y, acc.x, acc.y] {
+            value.to_bits().hash(hasher);
+        }
+        explosions.len().hash(hasher);
+        for point in explosions {
+            (point.x.to_bits(), point.y.to_bits()).hash(hasher);
+        }
+        pickups.hash(hasher);
+    }
+
+    /// Give back up to `amount` health to damaged and destroyed tiles, one
+    /// point per tile, starting from the bottom row. Destroyed tiles that
+    /// would come back inside one of the `bodies` are skipped. Returns how
+    /// much health was given back.
+    pub fn repair(&mut self, amount: u32, bodies: &[WorldRect]) -> u32 {
+        let mut repaired = 0;
+        for y in 0..GRID_HEIGHT {
+            for x in 0..GRID_WIDTH {
+                if repaired == amount {
+                    return repaired;
+                }
+                let original = self.original[y][x];
+                let max_health = match original.health() {
+                    Some(health) => health,
+                    None => continue,
+                };
+                match self.module[y][x] {
+                    Tile::Air => {
+                        let tile_point = self.to_world_coords((x, y));
+                        let rect = math::rect_from_point(tile_point, TILE_SIZE, TILE_SIZE);
+                        if bodies.iter().any(|body| body.overlaps(&rect)) {
                             continue;
                         }
-
-                        let idx = (0 + (GRID.tile_max_health - health)) as usize;
-                        batch.add(
-                            idx,
-                            DrawParam {
-                                dest,
-                                ..Default::default()
-                            },
-                        );
+                        self.module[y][x] = original.with_health(1);
+                        self.tiles_alive += 1;
                     }
+                    tile => match tile.health() {
+                        Some(health) if health < max_health => {
+                            self.module[y][x] = tile.with_health(health + 1);
+                        }
+                        _ => continue,
+                    },
                 }
+                repaired += 1;
+            }
+        }
+        repaired
+    }
+
+    pub fn draw(&mut self, ctx: &mut Context, images: &Images) -> GameResult<()> {
+        let mut batch = Batch::atlas(images.tiles.clone(), 16, 16);
+        for (j, row) in self.module.iter().enumerate() {
+            for (i, tile) in row.iter().enumerate() {
+                // Air and destroyed tiles don't need to be rendered
+                let (idx, color) = match tile.sprite() {
+                    Some(sprite) => sprite,
+                    None => continue,
+                };
+                batch.add(
+                    idx,
+                    DrawParam {
+                        dest: Point2::new(TILE_SIZE * i as f32, TILE_SIZE * j as f32),
+                        color: Some(color),
+                        ..Default::default()
+                    },
+                );
             }
         }
         let param = DrawParam {
@@ -129,26 +231,49 @@ impl Grid {
         batch.draw(ctx, param)?;
         Ok(())
     }
-    /// If sounds is None then no soundeffect is played
     pub fn damage_tile(&mut self, x: GridCoord, y: GridCoord) {
         use self::Tile::*;
+        if !self.module[y][x].destructible() {
+            return;
+        }
         match self.module[y][x] {
-            Solid(ref mut health) => {
+            Solid(ref mut health)
+            | OneWay(ref mut health)
+            | Reinforced(ref mut health)
+            | Explosive(ref mut health)
+            | Regen(ref mut health)
+            | Surface(_, ref mut health)
+            | Hazard(_, ref mut health) => {
                 if *health == 0 {
                     return;
                 }
 
                 *health -= 1;
                 if *health == 0 {
-                    self.tiles_alive -= 1;
+                    if let Explosive(_) = self.module[y][x] {
+                        let center = Vector2::new(TILE_SIZE / 2.0, TILE_SIZE / 2.0);
+                        self.explosions.push(self.to_world_coords((x, y)) + center);
+                    }
+                    // Reloading the config can make hazards destructible after
+                    // the grid counted its tiles, so they were never counted
+                    self.tiles_alive = self.tiles_alive.saturating_sub(1);
                     self.module[y][x] = Tile::Air;
                 }
             }
-            Leave | Start(_) | Air => (),
+            Leave | Start(_) | Bedrock | Air => (),
         }
     }
 
+    /// The centres of the explosive tiles destroyed since this was last called
+    pub fn take_explosions(&mut self) -> Vec<Point2> {
+        std::mem::take(&mut self.explosions)
+    }
+
     pub fn percent_tiles_alive(&self) -> f32 {
+        // Nothing to destroy, so the grid can't fall apart
+        if self.total_tiles == 0 {
+            return 1.0;
+        }
         self.tiles_alive as f32 / self.total_tiles as f32
     }
 
@@ -164,13 +289,26 @@ impl Grid {
         (y - self.world_offset.y) / GRID_TO_WORLD
     }
 
+    /// The tile covering a point in world space, if the point is inside this grid
+    pub fn tile_at(&self, point: Point2) -> Option<(Tile, GridCoord, GridCoord)> {
+        let x = self.to_grid_x(point.x);
+        let y = self.to_grid_y(point.y);
+        if x < 0.0 || y < 0.0 || x >= GRID_WIDTH as f32 || y >= GRID_HEIGHT as f32 {
+            return None;
+        }
+        let (x, y) = (x as GridCoord, y as GridCoord);
+        Some((self.module[y][x], x, y))
+    }
+
     pub fn overlapping_tiles(&self, rect: WorldRect, out: &mut Vec<(Tile, usize, usize)>) {
         let left = self.to_grid_x(rect.left());
         let right = self.to_grid_x(rect.right());
         let top = self.to_grid_y(rect.y + rect.h);
         let bottom = self.to_grid_y(rect.y);
 
-        if top < 0.0 || bottom > GRID_HEIGHT as f32 || left < 0.0 || right > GRID_WIDTH as f32 {
+        // Only give up when the rectangle misses the grid entirely, anything
+        // partially inside gets clamped to the edges below
+        if top < 0.0 || bottom > GRID_HEIGHT as f32 || right < 0.0 || left > GRID_WIDTH as f32 {
             return;
         }
 
@@ -189,7 +327,12 @@ impl Grid {
         }
     }
 
-    pub fn to_world_collider(&self, tile: (Tile, usize, usize)) -> WorldRect {
+    /// The area a tile covers in world space
+    pub fn tile_rect(&self, x: GridCoord, y: GridCoord) -> WorldRect {
+        math::rect_from_point(self.to_world_coords((x, y)), TILE_SIZE, TILE_SIZE)
+    }
+
+    pub fn to_world_collider(&self, tile: (Tile, usize, usize)) -> (WorldRect, ColliderKind) {
         use self::Tile::*;
         const NO_RECT: WorldRect = WorldRect {
             x: -100.0,
@@ -197,31 +340,50 @@ impl Grid {
             w: 0.0,
             h: 0.0,
         };
+        let rect = self.tile_rect(tile.1, tile.2);
         match tile.0 {
-            Start(_) | Solid(_) => {
-                let tile_point = self.to_world_coords((tile.1, tile.2));
-                math::rect_from_point(tile_point, TILE_SIZE, TILE_SIZE)
-            }
-            Leave => NO_RECT,
+            OneWay(_) => (rect, ColliderKind::OneWay),
+            Leave => (NO_RECT, ColliderKind::Solid),
             Air => unreachable!(),
+            _ => (rect, ColliderKind::Solid),
         }
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Hash)]
 pub enum GridState {
     Alive,
     Dead,
 }
 
-pub fn find_spawn_location(module: Module) -> Option<(GridCoord, GridCoord)> {
+/// A random empty tile with something to rest on below it, that doesn't
+/// already have a pickup
+pub fn find_pickup_location(grid: &Grid, rng: &mut impl Rng) -> Option<(GridCoord, GridCoord)> {
+    let mut spots = Vec::new();
+    for y in 1..GRID_HEIGHT {
+        for x in 0..GRID_WIDTH {
+            let below = grid.module[y - 1][x];
+            if grid.module[y][x] != Tile::Air || below == Tile::Air || below.hazard().is_some() {
+                continue;
+            }
+            let taken = |pickup: &Pickup| (pickup.x, pickup.y) == (x, y);
+            if grid.pickups.iter().any(taken) {
+                continue;
+            }
+            spots.push((x, y));
+        }
+    }
+    rng.choose(&spots).cloned()
+}
+
+pub fn find_spawn_location(module: Module, rng: &mut impl Rng) -> Option<(GridCoord, GridCoord)> {
     let mut columns: Vec<usize> = (1..GRID_WIDTH - 1).collect();
-    rand::thread_rng().shuffle(&mut columns);
+    rng.shuffle(&mut columns);
     for x in columns {
         for y in 0..(GRID_HEIGHT - 2) {
             let mut good_location = true;
             for i in x - 1..=x + 1 {
-                let ground_tile = module[y][i] != Tile::Air;
+                let ground_tile = module[y][i] != Tile::Air && module[y][i].hazard().is_none();
                 let tile_above = module[y + 1][i] == Tile::Air;
                 let tile_two_above = module[y + 2][i] == Tile::Air;
                 if !(ground_tile && tile_above && tile_two_above) {
@@ -243,67 +405,639 @@ fn total_tiles(module: Module) -> usize {
     let mut total_tiles = 0;
     for row in module.iter() {
         for tile in row.iter() {
-            match tile {
-                Tile::Air => continue,
-                _ => total_tiles += 1,
+            // Tiles that can't be destroyed would hold up grids forever
+            if tile.destructible() {
+                total_tiles += 1;
             }
         }
     }
     total_tiles
 }
 
+/// A module along with the metadata from its header
+#[derive(Debug, Clone)]
+pub struct ModuleDef {
+    pub name: String,
+    /// How likely the module is to be picked, relative to the others
+    pub weight: f32,
+    /// How far into a match the module should show up, starting at 0
+    pub tier: u32,
+    /// The game modes the module can be used in, empty means all of them
+    pub modes: Vec<String>,
+    pub tags: Vec<String>,
+    pub tiles: Module,
+    /// The line of the file where the first row is, for modules loaded from a file
+    pub line: Option<usize>,
+}
+
+impl ModuleDef {
+    pub fn new(name: String, tiles: Module) -> ModuleDef {
+        ModuleDef {
+            name,
+            weight: 1.0,
+            tier: 0,
+            modes: Vec::new(),
+            tags: Vec::new(),
+            tiles,
+            line: None,
+        }
+    }
+
+    pub fn allows_mode(&self, mode: &str) -> bool {
+        self.modes.is_empty() || self.modes.iter().any(|x| x == mode)
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|x| x == tag)
+    }
+
+    fn set_metadata(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "name" => {
+                // Unnamed modules are called '#1', '#2'... and saved without a name
+                if value.starts_with('#') {
+                    return Err(format!("Module names can't start with '#': '{}'", value));
+                }
+                self.name = value.to_string();
+            }
+            "weight" => {
+                self.weight = value
+                    .parse()
+                    .map_err(|err| format!("Invalid weight '{}': {}", value, err))?;
+                if !(self.weight >= 0.0 && self.weight.is_finite()) {
+                    return Err(format!("The weight can't be {}", value));
+                }
+            }
+            "tier" => {
+                self.tier = value
+                    .parse()
+                    .map_err(|err| format!("Invalid tier '{}': {}", value, err))?
+            }
+            "modes" => self.modes = parse_list(value),
+            "tags" => self.tags = parse_list(value),
+            _ => return Err(format!("Unknown module property '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+/// The lobby is the module tagged as one, or the first module in the file
+pub fn lobby_index(modules: &[ModuleDef]) -> usize {
+    modules.iter().position(|x| x.has_tag("lobby")).unwrap_or(0)
+}
+
 pub fn parse_modules_file<P: AsRef<path::Path>>(
     ctx: &mut Context,
     path: P,
-) -> GameResult<Vec<Module>> {
-    let file = ctx.filesystem.open(path)?;
-    // let contents = &fs::read_to_string(path).unwrap();
-    let mut modules_list = vec![];
-    let lines: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()?;
-
-    for module in lines.chunks(9) {
-        // last line in file, stop parsing
-        if module.len() == 1 {
-            assert_eq!(module[0].trim(), "");
-            break;
-        }
-        assert_eq!(module.len(), 9);
-        assert_eq!(module[8].trim(), "-");
-        let mut grid = [[Tile::Air; GRID_WIDTH]; GRID_HEIGHT];
-        for (i, row) in module[..8].iter().enumerate() {
-            grid[7 - i] = text_to_row(row)
-                .map_err(|err| format!("Could not parse {} (line: {}) Reason: {}", row, i, err))?;
-        }
-        modules_list.push(grid);
-    }
-    Ok(modules_list)
+) -> GameResult<Vec<ModuleDef>> {
+    let mut file = ctx.filesystem.open(path)?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    Ok(parse_modules(&text)?)
 }
 
-fn text_to_row(row: &str) -> Result<[Tile; GRID_WIDTH], String> {
-    let mut tiles = [Tile::Air; GRID_WIDTH];
-    for (i, character) in row.trim_right().chars().enumerate() {
-        match character {
-            '[' => tiles[i] = Tile::Start(0),
-            '!' => tiles[i] = Tile::Start(1),
-            ']' => tiles[i] = Tile::Start(2),
-            '?' => tiles[i] = Tile::Leave,
-            '#' => tiles[i] = Tile::Solid(GRID.tile_max_health),
-            ' ' => tiles[i] = Tile::Air,
+/// Each module is an optional header of `key: value` lines, then `GRID_HEIGHT`
+/// rows of tiles from top to bottom, then a line with a single `-`.
+/// Files without any headers are still valid, every module just gets the
+/// default metadata.
+///
+/// ```text
+/// name: lobby
+/// weight: 0.5
+/// tier: 2
+/// modes: classic, survival
+/// tags: lobby, narrow
+/// #      ...      #
+/// (the rest of the rows)
+/// -
+/// ```
+pub fn parse_modules(text: &str) -> Result<Vec<ModuleDef>, String> {
+    let modules: Vec<_> = parse_each_module(text)
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+    if modules.is_empty() {
+        return Err("The file doesn't contain any modules".into());
+    }
+    Ok(modules)
+}
+
+/// Like `parse_modules`, but a module with a mistake in it doesn't stop the
+/// ones after it from being read. Parsing picks up again after the next `-`.
+pub fn parse_each_module(text: &str) -> Vec<Result<ModuleDef, String>> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut modules = vec![];
+    let mut i = 0;
+    // Stop at the trailing blank lines at the end of the file
+    while lines.iter().skip(i).any(|x| !x.trim().is_empty()) {
+        let module = parse_module(&lines, &mut i, modules.len());
+        if module.is_err() {
+            // Skip the rest of the broken module
+            while i < lines.len() && lines[i].trim() != "-" {
+                i += 1;
+            }
+            i += 1;
+        }
+        modules.push(module);
+    }
+    modules
+}
+
+/// Parse the module starting at line `i`, leaving `i` on the line after it
+fn parse_module(lines: &[&str], i: &mut usize, index: usize) -> Result<ModuleDef, String> {
+    // Line numbers in errors start at 1
+    let error = |line: usize, name: &str, err: String| {
+        format!("line {} (module '{}'): {}", line + 1, name, err)
+    };
+
+    let name = format!("#{}", index + 1);
+    let mut module = ModuleDef::new(name, [[Tile::Air; GRID_WIDTH]; GRID_HEIGHT]);
+    while let Some((key, value)) = lines.get(*i).and_then(|x| split_metadata(x)) {
+        module
+            .set_metadata(key, value)
+            .map_err(|err| error(*i, &module.name, err))?;
+        *i += 1;
+    }
+
+    module.line = Some(*i + 1);
+    for row in 0..GRID_HEIGHT {
+        let line = match lines.get(*i) {
+            Some(line) if line.trim() != "-" => line,
             _ => {
-                return Err(format!(
-                    "Unknown Character: {} at position {}",
-                    character, i
+                return Err(error(
+                    *i,
+                    &module.name,
+                    format!("Expected {} rows, found {}", GRID_HEIGHT, row),
                 ));
             }
+        };
+        module.tiles[GRID_HEIGHT - 1 - row] =
+            text_to_row(line).map_err(|err| error(*i, &module.name, err))?;
+        *i += 1;
+    }
+
+    match lines.get(*i) {
+        Some(line) if line.trim() == "-" => *i += 1,
+        Some(line) => {
+            return Err(error(
+                *i,
+                &module.name,
+                format!("Expected '-' after the last row, found '{}'", line),
+            ));
         }
+        None => {
+            return Err(error(
+                *i,
+                &module.name,
+                "Expected '-' after the last row, found the end of the file".into(),
+            ));
+        }
+    }
+    Ok(module)
+}
+
+/// Split a header line like `tier: 2`, rows never contain letters so they
+/// can't be mistaken for one
+fn split_metadata(line: &str) -> Option<(&str, &str)> {
+    let colon = line.find(':')?;
+    let key = line[..colon].trim();
+    let is_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c == '_');
+    if is_key {
+        Some((key, line[colon + 1..].trim()))
+    } else {
+        None
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn text_to_row(row: &str) -> Result<[Tile; GRID_WIDTH], String> {
+    let mut tiles = [Tile::Air; GRID_WIDTH];
+    let row = row.trim_end();
+    let width = row.chars().count();
+    if width > GRID_WIDTH {
+        return Err(format!(
+            "The row is {} tiles wide, but modules are only {} wide",
+            width, GRID_WIDTH
+        ));
+    }
+    for (i, character) in row.chars().enumerate() {
+        tiles[i] = Tile::from_char(character)
+            .ok_or_else(|| format!("Unknown Character: {} at position {}", character, i))?;
     }
     Ok(tiles)
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Write modules out in the format `parse_modules` reads, only including the
+/// metadata that isn't the default
+pub fn write_modules(modules: &[ModuleDef]) -> String {
+    let mut text = String::new();
+    for module in modules {
+        // Unnamed modules get named after their position
+        if !module.name.starts_with('#') {
+            text += &format!("name: {}\n", module.name);
+        }
+        if module.weight != 1.0 {
+            text += &format!("weight: {}\n", module.weight);
+        }
+        if module.tier != 0 {
+            text += &format!("tier: {}\n", module.tier);
+        }
+        if !module.modes.is_empty() {
+            text += &format!("modes: {}\n", module.modes.join(", "));
+        }
+        if !module.tags.is_empty() {
+            text += &format!("tags: {}\n", module.tags.join(", "));
+        }
+        for row in module.tiles.iter().rev() {
+            text.extend(row.iter().map(|tile| tile.to_char()));
+            text.push('\n');
+        }
+        text += "-\n";
+    }
+    text
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum Tile {
     Air,
     Solid(u8),
     Start(u8),
     Leave,
+    /// Players can jump up through it and stand on top, bullets break it
+    OneWay(u8),
+    /// Hurts and knocks back players that touch it
+    Hazard(Hazard, u8),
+    /// Can't be destroyed
+    Bedrock,
+    /// Like `Solid`, but starts with `GRID.reinforced_max_health`
+    Reinforced(u8),
+    /// Like `Solid`, but changes how players move while standing on it
+    Surface(Surface, u8),
+    /// Blows up when destroyed, damaging everything nearby
+    Explosive(u8),
+    /// Heals back up to full health over time, as long as it isn't destroyed
+    Regen(u8),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Surface {
+    Ice,
+    Bounce,
+    ConveyorLeft,
+    ConveyorRight,
+}
+
+impl Surface {
+    fn color(self) -> Color {
+        match self {
+            Surface::Ice => Color::new(0.6, 0.9, 1.0, 1.0),
+            Surface::Bounce => Color::new(0.5, 1.0, 0.5, 1.0),
+            Surface::ConveyorLeft | Surface::ConveyorRight => Color::new(0.9, 0.7, 0.4, 1.0),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Hazard {
+    Spikes,
+    Lava,
+    Electric,
+}
+
+impl Hazard {
+    pub fn info(self) -> &'static HazardInfo {
+        match self {
+            Hazard::Spikes => &HAZARDS.spikes,
+            Hazard::Lava => &HAZARDS.lava,
+            Hazard::Electric => &HAZARDS.electric,
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Hazard::Spikes => Color::new(0.7, 0.7, 0.8, 1.0),
+            Hazard::Lava => Color::new(1.0, 0.4, 0.1, 1.0),
+            Hazard::Electric => Color::new(1.0, 1.0, 0.3, 1.0),
+        }
+    }
+}
+
+impl Tile {
+    /// The characters of every tile that can be placed in a module, besides air
+    pub const CHARS: &'static [char] = &[
+        '#', '%', '@', '=', '_', '+', '<', '>', '^', '~', ';', '*', '&', '[', '!', ']', '?',
+    ];
+
+    /// The index in the tile atlas and the tint to draw the tile with, or
+    /// `None` if there's nothing to draw
+    fn sprite(self) -> Option<(usize, Color)> {
+        use self::Tile::*;
+        let plain = Color::new(1.0, 1.0, 1.0, 1.0);
+        if self.health() == Some(0) {
+            return None;
+        }
+        // Damaged tiles use the sprites after the first one
+        let damage = |health: u8| GRID.tile_max_health.saturating_sub(health) as usize;
+        Some(match self {
+            Air => return None,
+            Start(idx) => (17 + idx as usize, plain),
+            Leave => (16, plain),
+            Solid(health) => (damage(health), plain),
+            OneWay(health) => (damage(health), Color::new(0.6, 0.8, 1.0, 0.6)),
+            Bedrock => (0, Color::new(0.35, 0.3, 0.4, 1.0)),
+            Reinforced(health) => {
+                // Spread the extra health over the same damage sprites
+                let lost = GRID.reinforced_max_health.saturating_sub(health) as usize;
+                let idx = lost * GRID.tile_max_health as usize
+                    / GRID.reinforced_max_health.max(1) as usize;
+                (idx, Color::new(0.75, 0.75, 0.9, 1.0))
+            }
+            Regen(health) => (damage(health), Color::new(0.8, 0.6, 1.0, 1.0)),
+            Explosive(health) => {
+                let idx = EXPLOSIVES.health.saturating_sub(health) as usize;
+                let idx = idx.min(GRID.tile_max_health as usize - 1);
+                (idx, Color::new(1.0, 0.3, 0.3, 1.0))
+            }
+            Surface(surface, health) => (damage(health), surface.color()),
+            Hazard(hazard, health) => (damage(health), hazard.color()),
+        })
+    }
+
+    /// The kind of hazard, if the tile is one that hasn't been destroyed
+    pub fn hazard(self) -> Option<Hazard> {
+        match self {
+            Tile::Hazard(hazard, health) if health > 0 => Some(hazard),
+            _ => None,
+        }
+    }
+
+    /// The kind of surface, if the tile is one that hasn't been destroyed
+    pub fn surface(self) -> Option<Surface> {
+        match self {
+            Tile::Surface(surface, health) if health > 0 => Some(surface),
+            _ => None,
+        }
+    }
+
+    /// The health of tiles that can be damaged, even once it's down to zero
+    pub fn health(self) -> Option<u8> {
+        use self::Tile::*;
+        match self {
+            Solid(health)
+            | OneWay(health)
+            | Reinforced(health)
+            | Explosive(health)
+            | Regen(health)
+            | Surface(_, health)
+            | Hazard(_, health) => Some(health),
+            Air | Start(_) | Leave | Bedrock => None,
+        }
+    }
+
+    /// Can bullets and explosions wear the tile down?
+    pub fn destructible(self) -> bool {
+        match self {
+            Tile::Hazard(hazard, _) => hazard.info().destructible,
+            _ => self.health().is_some(),
+        }
+    }
+
+    /// The same kind of tile with different health, tiles without health
+    /// stay as they are
+    pub fn with_health(self, health: u8) -> Tile {
+        use self::Tile::*;
+        match self {
+            Solid(_) => Solid(health),
+            OneWay(_) => OneWay(health),
+            Reinforced(_) => Reinforced(health),
+            Explosive(_) => Explosive(health),
+            Regen(_) => Regen(health),
+            Surface(surface, _) => Surface(surface, health),
+            Hazard(hazard, _) => Hazard(hazard, health),
+            Air | Start(_) | Leave | Bedrock => self,
+        }
+    }
+
+    pub fn from_char(character: char) -> Option<Tile> {
+        use self::Tile::*;
+        Some(match character {
+            '[' => Start(0),
+            '!' => Start(1),
+            ']' => Start(2),
+            '?' => Leave,
+            '#' => Solid(GRID.tile_max_health),
+            '=' => OneWay(GRID.tile_max_health),
+            '@' => Bedrock,
+            '%' => Reinforced(GRID.reinforced_max_health),
+            '*' => Explosive(EXPLOSIVES.health),
+            '&' => Regen(GRID.tile_max_health),
+            '_' => Surface(self::Surface::Ice, GRID.tile_max_health),
+            '+' => Surface(self::Surface::Bounce, GRID.tile_max_health),
+            '<' => Surface(self::Surface::ConveyorLeft, GRID.tile_max_health),
+            '>' => Surface(self::Surface::ConveyorRight, GRID.tile_max_health),
+            '^' => Hazard(self::Hazard::Spikes, GRID.tile_max_health),
+            '~' => Hazard(self::Hazard::Lava, GRID.tile_max_health),
+            ';' => Hazard(self::Hazard::Electric, GRID.tile_max_health),
+            ' ' => Air,
+            _ => return None,
+        })
+    }
+
+    /// The character for a tile in the module format, damage isn't saved
+    pub fn to_char(self) -> char {
+        use self::Tile::*;
+        match self {
+            Air => ' ',
+            Solid(0) => ' ',
+            Solid(_) => '#',
+            OneWay(0) => ' ',
+            OneWay(_) => '=',
+            Bedrock => '@',
+            Reinforced(0) => ' ',
+            Reinforced(_) => '%',
+            Explosive(0) => ' ',
+            Explosive(_) => '*',
+            Regen(0) => ' ',
+            Regen(_) => '&',
+            Surface(_, 0) => ' ',
+            Surface(self::Surface::Ice, _) => '_',
+            Surface(self::Surface::Bounce, _) => '+',
+            Surface(self::Surface::ConveyorLeft, _) => '<',
+            Surface(self::Surface::ConveyorRight, _) => '>',
+            Hazard(_, 0) => ' ',
+            Hazard(self::Hazard::Spikes, _) => '^',
+            Hazard(self::Hazard::Lava, _) => '~',
+            Hazard(self::Hazard::Electric, _) => ';',
+            Start(0) => '[',
+            Start(1) => '!',
+            Start(_) => ']',
+            Leave => '?',
+        }
+    }
+
+    /// A compact form for sending tiles over the network
+    pub fn encode(self) -> [u8; 2] {
+        use self::Tile::*;
+        match self {
+            Air => [0, 0],
+            Solid(health) => [1, health],
+            Start(idx) => [2, idx],
+            Leave => [3, 0],
+            OneWay(health) => [4, health],
+            Hazard(self::Hazard::Spikes, health) => [5, health],
+            Hazard(self::Hazard::Lava, health) => [6, health],
+            Hazard(self::Hazard::Electric, health) => [7, health],
+            Bedrock => [8, 0],
+            Reinforced(health) => [9, health],
+            Surface(self::Surface::Ice, health) => [10, health],
+            Surface(self::Surface::Bounce, health) => [11, health],
+            Surface(self::Surface::ConveyorLeft, health) => [12, health],
+            Surface(self::Surface::ConveyorRight, health) => [13, health],
+            Explosive(health) => [14, health],
+            Regen(health) => [15, health],
+        }
+    }
+
+    /// The inverse of `encode`, health is capped at what our own config
+    /// allows so a bad packet can't make tiles tougher than they should be
+    pub fn decode(bytes: [u8; 2]) -> Option<Tile> {
+        use self::Tile::*;
+        let health = bytes[1].min(GRID.tile_max_health);
+        Some(match bytes[0] {
+            0 => Air,
+            1 => Solid(health),
+            2 if bytes[1] <= 2 => Start(bytes[1]),
+            3 => Leave,
+            4 => OneWay(health),
+            5 => Hazard(self::Hazard::Spikes, health),
+            6 => Hazard(self::Hazard::Lava, health),
+            7 => Hazard(self::Hazard::Electric, health),
+            8 => Bedrock,
+            9 => Reinforced(bytes[1].min(GRID.reinforced_max_health)),
+            10 => Surface(self::Surface::Ice, health),
+            11 => Surface(self::Surface::Bounce, health),
+            12 => Surface(self::Surface::ConveyorLeft, health),
+            13 => Surface(self::Surface::ConveyorRight, health),
+            14 => Explosive(bytes[1].min(EXPLOSIVES.health)),
+            15 => Regen(health),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::tests::load_config;
+
+    /// A module with a floor and the given rows on top of it
+    fn module_text(rows: &[&str]) -> String {
+        let mut text = String::new();
+        for _ in rows.len()..GRID_HEIGHT - 1 {
+            text += "#                              #\n";
+        }
+        for row in rows {
+            text += row;
+            text += "\n";
+        }
+        text += "################################\n-\n";
+        text
+    }
+
+    #[test]
+    fn headerless_files_get_the_default_metadata() {
+        load_config();
+        let text = module_text(&[]) + &module_text(&["#  [!]  ?                      #"]);
+        let modules = parse_modules(&text).unwrap();
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[1].name, "#2");
+        assert_eq!(modules[1].weight, 1.0);
+        assert_eq!(modules[1].tier, 0);
+        assert!(modules[1].modes.is_empty() && modules[1].tags.is_empty());
+        assert_eq!(modules[1].line, Some(GRID_HEIGHT + 2));
+        assert_eq!(modules[1].tiles[1][3], Tile::Start(0));
+        assert_eq!(modules[1].tiles[1][8], Tile::Leave);
+    }
+
+    #[test]
+    fn headers_set_every_key() {
+        load_config();
+        let header = "name: ledges\nweight: 0.5\ntier: 2\nmodes: classic, survival\ntags: lobby\n";
+        let modules = parse_modules(&(header.to_string() + &module_text(&[]))).unwrap();
+        let module = &modules[0];
+        assert_eq!(module.name, "ledges");
+        assert_eq!(module.weight, 0.5);
+        assert_eq!(module.tier, 2);
+        assert_eq!(module.modes, ["classic", "survival"]);
+        assert!(module.has_tag("lobby"));
+        assert_eq!(module.line, Some(6));
+    }
+
+    #[test]
+    fn bad_files_report_the_line() {
+        load_config();
+        let bad_value = "tier: high\n".to_string() + &module_text(&[]);
+        let err = parse_modules(&bad_value).unwrap_err();
+        assert!(err.starts_with("line 1 "), "{}", err);
+        assert!(err.contains("Invalid tier 'high'"), "{}", err);
+
+        let short = "name: short\n#    #\n-\n";
+        let err = parse_modules(short).unwrap_err();
+        assert!(err.starts_with("line 3 (module 'short')"), "{}", err);
+        assert!(err.contains("found 1"), "{}", err);
+
+        let wide = module_text(&[]) + &module_text(&["#                                #"]);
+        let err = parse_modules(&wide).unwrap_err();
+        let line = GRID_HEIGHT + 1 + GRID_HEIGHT - 1;
+        assert!(
+            err.starts_with(&format!("line {} (module '#2')", line)),
+            "{}",
+            err
+        );
+        assert!(err.contains("34 tiles wide"), "{}", err);
+
+        // Trailing whitespace doesn't count towards the width
+        let padded = module_text(&["#                              #    "]);
+        assert!(parse_modules(&padded).is_ok());
+    }
+
+    #[test]
+    fn broken_modules_dont_hide_the_rest() {
+        load_config();
+        let text =
+            module_text(&[]) + "tier: x\n" + &module_text(&[]) + "#    #\n-\n" + &module_text(&[]);
+        let modules = parse_each_module(&text);
+        assert_eq!(modules.len(), 4);
+        assert!(modules[0].is_ok() && modules[3].is_ok());
+        assert!(modules[1].is_err() && modules[2].is_err());
+        assert_eq!(modules[3].as_ref().unwrap().name, "#4");
+    }
+
+    #[test]
+    fn written_modules_parse_back_the_same() {
+        load_config();
+        let text = "name: mixed\nweight: 2.5\ntier: 1\nmodes: survival\ntags: narrow, tall\n"
+            .to_string()
+            + &module_text(&["#  %@=_+<>^~;*&  [!]  ?        #"])
+            + &module_text(&[]);
+        let modules = parse_modules(&text).unwrap();
+        let written = write_modules(&modules);
+        assert_eq!(written, text);
+        let parsed = parse_modules(&written).unwrap();
+        for (a, b) in modules.iter().zip(&parsed) {
+            assert_eq!(a.name, b.name);
+            assert_eq!(a.weight, b.weight);
+            assert_eq!(a.tier, b.tier);
+            assert_eq!(a.modes, b.modes);
+            assert_eq!(a.tags, b.tags);
+            assert_eq!(a.tiles, b.tiles);
+        }
+    }
 }
@@ -0,0 +1,30 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+pub mod bot;
+pub mod bullet;
+pub mod codec;
+pub mod collide;
+pub mod config;
+pub mod draw;
+pub mod editor;
+pub mod explosion;
+pub mod generator;
+pub mod grid;
+pub mod images;
+pub mod math;
+pub mod net;
+pub mod pickup;
+pub mod player;
+pub mod reach;
+pub mod replay;
+pub mod rollback;
+pub mod selector;
+pub mod simulation;
+pub mod sound;
+pub mod weapon;
+
+pub use crate::images::Images;
+
+pub const DT: f32 = 1.0 / 60.0;
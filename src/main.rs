@@ -1,8 +1,4 @@
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
-
-use std::path;
+use std::{path::PathBuf, time::Duration};
 
 use ggez::{
     conf::{WindowMode, WindowSetup},
@@ -10,30 +6,28 @@ use ggez::{
     graphics::{self, Color, DrawParam, Point2, Vector2},
     timer, Context, ContextBuilder, GameResult,
 };
-use rand::{thread_rng, Rng};
-
-use crate::bullet::Bullet;
-use crate::config::{GRID, MENU, PLAYER, TEAM};
-use crate::grid::{Grid, GridState, Module};
-use crate::images::Images;
-use crate::player::{Axis, Button, Controls, Player, Team};
-use crate::sound::{Sound, SoundEffect};
-
-mod bullet;
-mod collide;
-mod config;
-mod draw;
-mod grid;
-mod images;
-mod math;
-mod player;
-mod sound;
+use rand::Rng;
+
+use fall::bot::{Bot, Difficulty};
+use fall::config::{self, GAME, MENU, PLAYER, TEAM};
+use fall::editor::Editor;
+use fall::images::{self, Images};
+use fall::net::{Client, ClientEvent, Host, HostEvent};
+use fall::player::{self, Axis, Button, ControlState, Controls, Player, Team};
+use fall::replay::{Record, Recorder, Replay};
+use fall::rollback::{self, Session};
+use fall::simulation::{somes_mut, Event, Inputs, Simulation};
+use fall::sound::{self, Sound};
+use fall::{draw, grid, pickup};
 
 fn joycon_controls(id: i32) -> Controls {
     Controls {
         lr: Axis::Analog(id, event::Axis::LeftX),
+        aim: Axis::Analog(id, event::Axis::RightY),
         jump: Button::Controller(id, event::Button::A),
         shoot: Button::Controller(id, event::Button::B),
+        repair: Button::Controller(id, event::Button::X),
+        dash: Button::Controller(id, event::Button::Y),
     }
 }
 
@@ -42,8 +36,14 @@ const WASD_CONTROLS: Controls = Controls {
         Button::Keyboard(event::Keycode::A),
         Button::Keyboard(event::Keycode::D),
     ),
+    aim: Axis::Buttons(
+        Button::Keyboard(event::Keycode::S),
+        Button::Keyboard(event::Keycode::W),
+    ),
     jump: Button::Keyboard(event::Keycode::W),
     shoot: Button::Keyboard(event::Keycode::Tab),
+    repair: Button::Keyboard(event::Keycode::Q),
+    dash: Button::Keyboard(event::Keycode::LShift),
 };
 
 const ARROW_CONTROLS: Controls = Controls {
@@ -51,270 +51,455 @@ const ARROW_CONTROLS: Controls = Controls {
         Button::Keyboard(event::Keycode::Left),
         Button::Keyboard(event::Keycode::Right),
     ),
+    aim: Axis::Buttons(
+        Button::Keyboard(event::Keycode::Down),
+        Button::Keyboard(event::Keycode::Up),
+    ),
     jump: Button::Keyboard(event::Keycode::Up),
     shoot: Button::Keyboard(event::Keycode::Comma),
+    repair: Button::Keyboard(event::Keycode::Period),
+    dash: Button::Keyboard(event::Keycode::Slash),
 };
 
-const DT: f32 = 1.0 / 60.0;
 const MODULES_PATH: &str = "/modules.txt";
 
-#[derive(PartialEq)]
-enum MenuPhase {
-    InMenu,
-    DropMenu,
-    InGame,
-}
-
 struct MainState {
     focused: bool,
-    in_menu: bool,
-    // Grids are stored from lowest visually to highest
-    grids: Vec<Grid>,
-    modules: Vec<Module>,
-    players: [Option<Player>; 4],
-    bullets: Vec<Bullet>,
+    seed: u64,
+    sim: Simulation,
+    controls: [Option<Controls>; 4],
+    bots: [Option<Bot>; 4],
+    bot_difficulty: Difficulty,
+    inputs: Inputs,
     images: Images,
     sounds: Sound,
-    menu_phase: MenuPhase,
-}
-
-fn somes_mut<'a, T: 'a>(
-    i: impl IntoIterator<Item = &'a mut Option<T>>,
-) -> impl Iterator<Item = &'a mut T> {
-    i.into_iter().filter_map(|x| x.as_mut())
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    host: Option<Host>,
+    client: Option<Client>,
+    session: Option<Session>,
+    editor: Option<Editor>,
 }
 
 impl MainState {
-    fn new(ctx: &mut Context) -> GameResult<MainState> {
+    fn new(ctx: &mut Context, args: &Args) -> GameResult<MainState> {
+        config::load(ctx)?;
         let modules =
             grid::parse_modules_file(ctx, MODULES_PATH).expect("Should load the modules file");
-        let grids = vec![
-            Grid::new_from_module((grid::GRID_HEIGHT * 0) as f32, modules[0].clone()),
-            Grid::new_from_module((grid::GRID_HEIGHT * 1) as f32, modules[0].clone()),
-            Grid::new_from_module((grid::GRID_HEIGHT * 2) as f32, modules[0].clone()),
-        ];
+
+        let fingerprint = config::fingerprint(ctx, &[MODULES_PATH])?;
+        let replay = match &args.replay {
+            Some(path) => {
+                let replay = Replay::open(path)
+                    .map_err(|err| format!("Could not load replay '{}': {}", path, err))?;
+                if replay.fingerprint != fingerprint {
+                    let err = "was recorded with a different config or modules file";
+                    return Err(format!("Replay '{}' {}", path, err).into());
+                }
+                Some(replay)
+            }
+            None => None,
+        };
+
+        let seed = replay
+            .as_ref()
+            .map(|replay| replay.seed)
+            .or(args.seed)
+            .or(GAME.seed)
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        println!("Seed: {}", seed);
+
+        let recorder = match &args.record {
+            Some(path) => Some(
+                Recorder::create(path, seed, fingerprint)
+                    .map_err(|err| format!("Could not create '{}': {}", path, err))?,
+            ),
+            None => None,
+        };
+
+        let host = match args.host {
+            Some(port) => Some(
+                Host::bind(port, fingerprint)
+                    .map_err(|err| format!("Could not host on {}: {}", port, err))?,
+            ),
+            None => None,
+        };
+        let client = match &args.connect {
+            Some(addr) => Some(
+                Client::connect(addr, fingerprint)
+                    .map_err(|err| format!("Could not connect to '{}': {}", addr, err))?,
+            ),
+            None => None,
+        };
+
+        let session = match &args.peer {
+            Some(options) => Some(
+                Session::new(options, seed)
+                    .map_err(|err| format!("Could not reach '{}': {}", options.remote, err))?,
+            ),
+            None => None,
+        };
+
+        let editor = if args.edit {
+            Some(Editor::new(modules.clone(), &modules_file(), seed))
+        } else {
+            None
+        };
 
         let images = images::Images::new(ctx)?;
-        let sounds = sound::Sound::new(ctx)?;
-        config::load(ctx)?;
+        let sounds = sound::Sound::new(ctx, seed)?;
 
         Ok(MainState {
             focused: true,
-            in_menu: true,
-            grids,
-            modules,
-            players: [None, None, None, None],
-            bullets: Vec::with_capacity(20),
+            seed,
+            sim: Simulation::new(modules, seed),
+            controls: [None, None, None, None],
+            bots: [None, None, None, None],
+            bot_difficulty: Difficulty::Normal,
+            inputs: Inputs::default(),
             images,
             sounds,
-            menu_phase: MenuPhase::InMenu,
+            recorder,
+            replay,
+            host,
+            client,
+            session,
+            editor,
         })
     }
 
     fn button(&mut self, btn: Button, pressed: bool) {
+        // The replay is in control of every player
+        if self.replay.is_some() || self.editor.is_some() {
+            return;
+        }
+
+        self.record(Record::Button(btn, pressed));
+        self.press(btn, pressed);
+    }
+
+    /// What a button does, whether it was just pressed or read back from a replay
+    fn press(&mut self, btn: Button, pressed: bool) {
         let mut found = false;
-        for player in somes_mut(&mut self.players) {
-            if btn == player.controls.jump {
-                player.control_state.jump = pressed;
-                found = true;
-            }
-            if btn == player.controls.shoot {
-                player.control_state.shoot = pressed;
-                found = true;
-            }
-            if let Axis::Buttons(ref l, ref r) = player.controls.lr {
-                if btn == *l {
-                    if pressed {
-                        player.control_state.facing = -1.0;
-                    } else if player.control_state.r_pressed {
-                        player.control_state.facing = 1.0;
-                    }
-                    player.control_state.l_pressed = pressed;
-                    found = true;
-                }
-                if btn == *r {
-                    if pressed {
-                        player.control_state.facing = 1.0;
-                    } else if player.control_state.l_pressed {
-                        player.control_state.facing = -1.0;
-                    }
-                    player.control_state.r_pressed = pressed;
-                    found = true;
-                }
+        for (controls, input) in self.controls.iter().zip(&mut self.inputs) {
+            if let Some(controls) = controls {
+                found |= controls.button(input, &btn, pressed);
             }
         }
 
         if !found && pressed {
-            if let Some((i, player)) = self
-                .players
-                .iter_mut()
-                .enumerate()
-                .find(|(_, x)| x.is_none())
-            {
-                match btn {
-                    Button::Keyboard(event::Keycode::Up) => {
-                        *player = Some(Player::new(Team(i as u8), ARROW_CONTROLS));
-                    }
-                    Button::Keyboard(event::Keycode::W) => {
-                        *player = Some(Player::new(Team(i as u8), WASD_CONTROLS));
-                    }
-                    Button::Controller(id, event::Button::A) => {
-                        *player = Some(Player::new(Team(i as u8), joycon_controls(id)));
-                    }
-                    _ => (),
+            let controls = match btn {
+                Button::Keyboard(event::Keycode::Up) => ARROW_CONTROLS,
+                Button::Keyboard(event::Keycode::W) => WASD_CONTROLS,
+                Button::Controller(id, event::Button::A) => joycon_controls(id),
+                Button::Keyboard(event::Keycode::B) => return self.add_bot(),
+                Button::Keyboard(event::Keycode::Backspace) => return self.remove_bot(),
+                Button::Keyboard(event::Keycode::E) => return self.open_editor(),
+                Button::Keyboard(event::Keycode::N) => {
+                    self.bot_difficulty = self.bot_difficulty.next();
+                    println!("New bots will be {:?}", self.bot_difficulty);
+                    return;
                 }
-            }
+                _ => return,
+            };
+            self.join(controls);
+        }
+    }
+
+    /// Put a new player with the given controls in the first free slot
+    fn join(&mut self, controls: Controls) {
+        if let Some(client) = &mut self.client {
+            client.join(controls);
+            return;
+        }
+        // Both peers always have a player, this just picks which controls drive ours
+        if let Some(session) = &self.session {
+            self.controls[session.local_slot()] = Some(controls);
+            return;
+        }
+        if let Some(i) = self.sim.players.iter().position(|x| x.is_none()) {
+            self.join_slot(i, Some(controls), false);
+        }
+    }
+
+    fn join_slot(&mut self, slot: usize, controls: Option<Controls>, ready: bool) {
+        let mut player = Player::new(Team(slot as u8));
+        player.ready = ready;
+        self.sim.players[slot] = Some(player);
+        self.controls[slot] = controls;
+        self.inputs[slot] = ControlState::default();
+    }
+
+    fn leave_slot(&mut self, slot: usize) {
+        self.sim.players[slot] = None;
+        self.controls[slot] = None;
+        self.bots[slot] = None;
+        self.inputs[slot] = ControlState::default();
+        if let Some(host) = &mut self.host {
+            host.release(slot);
+        }
+    }
+
+    fn add_bot(&mut self) {
+        if !self.sim.in_menu || self.client.is_some() || self.session.is_some() {
+            return;
+        }
+        if let Some(i) = self.sim.players.iter().position(|x| x.is_none()) {
+            // Bots don't need to shoot the start button to be ready
+            self.join_slot(i, None, true);
+            self.bots[i] = Some(Bot::new(i, self.bot_difficulty, self.seed));
+        }
+    }
+
+    fn remove_bot(&mut self) {
+        if !self.sim.in_menu || self.client.is_some() || self.session.is_some() {
+            return;
+        }
+        if let Some(i) = self.bots.iter().rposition(|x| x.is_some()) {
+            self.leave_slot(i);
+        }
+    }
+
+    /// The editor can only be opened from the menu of a local game, since
+    /// leaving it restarts the simulation with the edited modules
+    fn open_editor(&mut self) {
+        let networked = self.host.is_some() || self.client.is_some() || self.session.is_some();
+        let taped = self.recorder.is_some() || self.replay.is_some();
+        if !self.sim.in_menu || networked || taped {
+            return;
+        }
+        let modules = self.sim.modules.to_vec();
+        self.editor = Some(Editor::new(modules, &modules_file(), self.seed));
+    }
+
+    fn close_editor(&mut self) {
+        let editor = match self.editor.take() {
+            Some(editor) => editor,
+            None => return,
+        };
+        let players = self.sim.players.clone();
+        self.sim = Simulation::new(editor.modules().to_vec(), self.seed);
+        // Anyone who had joined respawns in the new lobby
+        for (slot, player) in self.sim.players.iter_mut().zip(players.iter()) {
+            *slot = player.clone().map(|mut player| {
+                player.alive = false;
+                player
+            });
         }
     }
 
     fn axis(&mut self, axis: event::Axis, id: i32, value: f32) {
+        if self.replay.is_some() || self.editor.is_some() {
+            return;
+        }
+
+        self.record(Record::Axis(id, axis, value));
+        self.move_stick(axis, id, value);
+    }
+
+    fn move_stick(&mut self, axis: event::Axis, id: i32, value: f32) {
         let axis = Axis::Analog(id, axis);
-        for player in somes_mut(&mut self.players) {
-            if axis == player.controls.lr {
-                if value.abs() > 0.1 {
-                    player.control_state.facing = value.signum();
-                }
-                player.control_state.lr = value;
+        for (controls, input) in self.controls.iter().zip(&mut self.inputs) {
+            if let Some(controls) = controls {
+                controls.axis(input, &axis, value);
             }
         }
     }
 }
 
-pub fn draw_pos(p: Point2) -> Point2 {
-    Point2::new(p.x, 24.0 - p.y)
-}
+impl MainState {
+    fn tick(&mut self, ctx: &mut Context) {
+        if let Some(editor) = &mut self.editor {
+            for event in editor.tick() {
+                if let Event::Sound(effect) = event {
+                    self.sounds.play_sound(ctx, *effect);
+                }
+            }
+            return;
+        }
+        if self.client.is_some() {
+            return self.client_tick(ctx);
+        }
+        if self.session.is_some() {
+            return self.session_tick(ctx);
+        }
+        if self.host.is_some() {
+            self.poll_host();
+        }
 
-impl ggez::event::EventHandler for MainState {
-    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
-        const DESIRED_FPS: u32 = 60;
+        if self.replay.is_some() {
+            self.read_replay();
+        }
+        // Bots only depend on the simulation, so replays don't need to store them
+        for (bot, input) in self.bots.iter_mut().zip(&mut self.inputs) {
+            if let Some(bot) = bot {
+                *input = bot.think(&self.sim);
+            }
+        }
+        // Record what every player ended up doing, not just the raw buttons
+        for slot in 0..self.inputs.len() {
+            if self.sim.players[slot].is_some() {
+                let input = self.inputs[slot];
+                self.record(Record::Input(slot, input));
+            }
+        }
+        self.record(Record::Tick);
+
+        let mut left = Vec::new();
+        let mut sounds = Vec::new();
+        for event in self.sim.step(&self.inputs) {
+            match event {
+                Event::Sound(effect) => {
+                    self.sounds.play_sound(ctx, *effect);
+                    sounds.push(*effect);
+                }
+                Event::PlayerLeft(team) => left.push(team.0 as usize),
+                _ => (),
+            }
+        }
+        for slot in left {
+            self.leave_slot(slot);
+        }
 
-        if !self.focused {
-            while timer::check_update_time(ctx, DESIRED_FPS) {}
-            timer::sleep(std::time::Duration::from_millis(10));
-            timer::yield_now();
-            return Ok(());
+        if let Some(host) = &mut self.host {
+            host.broadcast(&self.sim, &sounds);
         }
+    }
 
-        if self.in_menu {
-            let mut ready = true;
-            let mut player_count = 0;
-            for player in somes_mut(&mut self.players) {
-                ready &= player.ready;
-                player_count += 1;
-            }
-            if ready && player_count >= 2 {
-                self.in_menu = false;
-                self.menu_phase = MenuPhase::DropMenu;
-                for grid in &mut self.grids {
-                    if grid.state == GridState::Alive {
-                        grid.state = GridState::Dead;
+    fn poll_host(&mut self) {
+        let mut events = Vec::new();
+        if let Some(host) = &mut self.host {
+            host.poll(&mut events);
+        }
+        for event in events {
+            match event {
+                HostEvent::Join { addr, request } => {
+                    let slot = self.sim.players.iter().position(|x| x.is_none());
+                    if let Some(slot) = slot {
+                        self.join_slot(slot, None, false);
+                        self.record(Record::Join { slot, ready: false });
+                    }
+                    if let Some(host) = &mut self.host {
+                        match slot {
+                            Some(slot) => host.accept(addr, request, slot),
+                            None => host.reject(addr, request),
+                        }
                     }
                 }
-                for player in somes_mut(&mut self.players) {
-                    player.alive = false;
-                    player.lives = PLAYER.max_lives;
+                HostEvent::Input(slot, state) => self.inputs[slot] = state,
+                HostEvent::Left(slot) => {
+                    self.leave_slot(slot);
+                    self.record(Record::Leave(slot));
                 }
             }
         }
+    }
 
-        match self.menu_phase {
-            MenuPhase::InMenu => (),
-            MenuPhase::DropMenu => {
-                if self
-                    .grids
-                    .iter()
-                    .all(|x| x.state == GridState::Alive && x.vel.y == 0.0)
-                {
-                    self.menu_phase = MenuPhase::InGame;
+    /// Clients don't simulate anything, they just follow the host's snapshots
+    fn client_tick(&mut self, ctx: &mut Context) {
+        let mut events = Vec::new();
+        if let Some(client) = &mut self.client {
+            client.send_inputs(&self.inputs);
+            client.poll(&mut self.sim, &mut events);
+        }
+        for event in events {
+            match event {
+                ClientEvent::Joined(slot, controls) => {
+                    self.controls[slot] = Some(controls);
+                    self.inputs[slot] = ControlState::default();
+                }
+                ClientEvent::Left(slot) => {
+                    self.controls[slot] = None;
+                    self.inputs[slot] = ControlState::default();
                 }
+                ClientEvent::Sound(effect) => self.sounds.play_sound(ctx, effect),
             }
-            MenuPhase::InGame => (),
         }
+    }
 
-        for i in 0..self.grids.len() {
-            if i == 0 {
-                let target = if self.grids[0].state == GridState::Dead {
-                    -(grid::GRID_HEIGHT as f32)
-                } else {
-                    0.0
-                };
-                self.grids[i].fixed_update(ctx, &mut self.sounds, target);
-            } else {
-                let offset = self.grids[i - 1].world_offset.y + grid::GRID_HEIGHT as f32;
-                self.grids[i].fixed_update(ctx, &mut self.sounds, offset);
-            }
+    /// The session owns the simulation during a peer to peer match, it may rewind
+    /// and replay ticks whenever the remote player's inputs arrive
+    fn session_tick(&mut self, ctx: &mut Context) {
+        let mut events = Vec::new();
+        if let Some(session) = &mut self.session {
+            let local = self.inputs[session.local_slot()];
+            session.tick(&mut self.sim, local, &mut events);
         }
-
-        for player in somes_mut(&mut self.players) {
-            player.update(ctx, &mut self.bullets, &mut self.sounds);
+        for event in events {
+            if let Event::Sound(effect) = event {
+                self.sounds.play_sound(ctx, effect);
+            }
         }
+    }
 
-        while timer::check_update_time(ctx, DESIRED_FPS) {
-            // fixed update
-            for player in somes_mut(&mut self.players) {
-                player.fixed_update(&self.grids);
-
-                // If the player is dead attempt to respawn them
-                if !player.alive && self.menu_phase != MenuPhase::DropMenu {
-                    let mut indicies: Vec<_> = (0..self.grids.len()).collect();
-                    rand::thread_rng().shuffle(&mut indicies);
-                    for i in indicies {
-                        // Avoid spawning on the lowest grid if too damanged
-                        // (might be instant death!)
-                        if i == 0 && self.grids[0].percent_tiles_alive() < GRID.no_spawn_threshold {
-                            continue;
-                        }
-                        // Don't spawn above the screen.
-                        if self.grids[i].world_offset.y > draw::WORLD_HEIGHT {
-                            continue;
-                        }
-                        if player.respawn(&self.grids[i]) {
-                            break;
-                        }
-                    }
-
-                    if !player.alive {
-                        println!("Player {:?} cant find a spot", player.team);
-                    }
+    /// Apply the recorded input up to the end of the next tick, through the
+    /// same paths as live input
+    fn read_replay(&mut self) {
+        loop {
+            let record = match self.replay.as_mut().map(Replay::next_record) {
+                Some(Ok(Some(record))) => record,
+                Some(Ok(None)) => {
+                    println!("Replay finished");
+                    self.replay = None;
+                    self.inputs = Inputs::default();
+                    return;
+                }
+                Some(Err(err)) => {
+                    println!("Error reading replay: {}", err);
+                    self.replay = None;
+                    self.inputs = Inputs::default();
+                    return;
                 }
+                None => return,
+            };
+            match record {
+                Record::Tick => return,
+                Record::Button(btn, pressed) => self.press(btn, pressed),
+                Record::Axis(id, axis, value) => self.move_stick(axis, id, value),
+                Record::Input(slot, state) => {
+                    // The sticks aren't recorded, the axis records keep them up to date
+                    let input = &mut self.inputs[slot];
+                    *input = ControlState {
+                        aim_stick: input.aim_stick,
+                        move_stick: input.move_stick,
+                        ..state
+                    };
+                }
+                Record::Join { slot, ready } => self.join_slot(slot, None, ready),
+                Record::Leave(slot) => self.leave_slot(slot),
             }
+        }
+    }
 
-            for bullet in &mut self.bullets {
-                bullet.fixed_update(
-                    ctx,
-                    &mut self.sounds,
-                    &mut self.grids,
-                    &mut self.players,
-                    self.in_menu,
-                );
-            }
-            self.bullets.retain(|bullet| bullet.is_alive);
-
-            if thread_rng().gen_bool(0.2) {
-                let grid_id = *thread_rng()
-                    .choose(&[
-                        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2,
-                    ])
-                    .unwrap();
-                self.grids[grid_id].damage_tile(
-                    thread_rng().gen_range(0, grid::GRID_WIDTH),
-                    thread_rng().gen_range(0, grid::GRID_HEIGHT),
-                );
+    fn record(&mut self, record: Record) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(&record) {
+                println!("Stopped recording: {}", err);
+                self.recorder = None;
             }
         }
+    }
+}
+
+pub fn draw_pos(p: Point2) -> Point2 {
+    Point2::new(p.x, 24.0 - p.y)
+}
 
-        if self.grids.len() > 0 && self.grids[0].world_offset.y <= -(grid::GRID_HEIGHT as f32) {
-            self.grids.remove(0);
-            self.grids.push(Grid::new_from_module(
-                grid::GRID_HEIGHT as f32 * 3.0,
-                rand::thread_rng().choose(&self.modules).unwrap().clone(),
-            ));
-            self.sounds.play_sound(ctx, SoundEffect::GridFallOffscreen);
+impl ggez::event::EventHandler for MainState {
+    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
+        const DESIRED_FPS: u32 = 60;
+
+        if !self.focused {
+            while timer::check_update_time(ctx, DESIRED_FPS) {}
+            timer::sleep(std::time::Duration::from_millis(10));
+            timer::yield_now();
+            return Ok(());
         }
 
-        for i in 0..self.grids.len() {
-            self.grids[i].update();
+        while timer::check_update_time(ctx, DESIRED_FPS) {
+            self.tick(ctx);
         }
+
         self.sounds.update();
         timer::yield_now();
         Ok(())
@@ -329,15 +514,22 @@ impl ggez::event::EventHandler for MainState {
         graphics::set_background_color(ctx, Color::new(0.0, 0.0, 0.0, 1.0));
         graphics::clear(ctx);
 
-        for grid in &mut self.grids {
+        if let Some(editor) = &mut self.editor {
+            editor.draw(ctx, &self.images)?;
+            graphics::present(ctx);
+            return Ok(());
+        }
+
+        for grid in &mut self.sim.grids {
             grid.draw(ctx, &self.images)?;
+            pickup::draw(ctx, &self.images, grid)?;
         }
 
-        for player in somes_mut(&mut self.players) {
+        for player in somes_mut(&mut self.sim.players) {
             player.draw(ctx, &self.images)?;
         }
 
-        for bullet in &self.bullets {
+        for bullet in &self.sim.bullets {
             bullet.draw(ctx, &self.images)?;
         }
 
@@ -345,7 +537,7 @@ impl ggez::event::EventHandler for MainState {
         let mut lives = draw::Batch::atlas(self.images.lives.clone(), 2, 1);
         let mut ready = draw::Batch::atlas(self.images.ready.clone(), 1, 1);
         let a = if time % 1.5 < 0.8 { 1.0 } else { 0.25 };
-        for ((player, info), &color) in self.players.iter().zip(&MENU.pos).zip(&TEAM.colors) {
+        for ((player, info), &color) in self.sim.players.iter().zip(&MENU.pos).zip(&TEAM.colors) {
             let join_pos = Point2::new(info.join_pos.0, info.join_pos.1);
             let heart_pos = Point2::new(info.heart_pos.0, info.heart_pos.1);
             let ready_pos = Point2::new(info.ready_pos.0, info.ready_pos.1);
@@ -366,7 +558,7 @@ impl ggez::event::EventHandler for MainState {
                     );
                 }
 
-                if self.in_menu {
+                if self.sim.in_menu {
                     if player.ready {
                         ready.add(
                             0,
@@ -392,7 +584,7 @@ impl ggez::event::EventHandler for MainState {
                     }
                 }
             } else {
-                if self.in_menu {
+                if self.sim.in_menu {
                     draw::draw_sprite(
                         ctx,
                         &self.images.join,
@@ -417,9 +609,15 @@ impl ggez::event::EventHandler for MainState {
         &mut self,
         _ctx: &mut Context,
         keycode: event::Keycode,
-        _keymod: event::Mod,
+        keymod: event::Mod,
         _repeat: bool,
     ) {
+        if let Some(editor) = &mut self.editor {
+            if !editor.key(keycode, keymod, true) {
+                self.close_editor();
+            }
+            return;
+        }
         self.button(player::Button::Keyboard(keycode), true);
     }
 
@@ -427,12 +625,44 @@ impl ggez::event::EventHandler for MainState {
         &mut self,
         _ctx: &mut Context,
         keycode: event::Keycode,
-        _keymod: event::Mod,
+        keymod: event::Mod,
         _repeat: bool,
     ) {
+        if let Some(editor) = &mut self.editor {
+            editor.key(keycode, keymod, false);
+            return;
+        }
         self.button(player::Button::Keyboard(keycode), false);
     }
 
+    fn mouse_button_down_event(
+        &mut self,
+        _ctx: &mut Context,
+        button: event::MouseButton,
+        x: i32,
+        y: i32,
+    ) {
+        if let Some(editor) = &mut self.editor {
+            let left = button == event::MouseButton::Left;
+            let right = button == event::MouseButton::Right;
+            editor.mouse(x, y, left, right);
+        }
+    }
+
+    fn mouse_motion_event(
+        &mut self,
+        _ctx: &mut Context,
+        state: event::MouseState,
+        x: i32,
+        y: i32,
+        _xrel: i32,
+        _yrel: i32,
+    ) {
+        if let Some(editor) = &mut self.editor {
+            editor.mouse(x, y, state.left(), state.right());
+        }
+    }
+
     fn controller_button_down_event(
         &mut self,
         _ctx: &mut Context,
@@ -464,7 +694,11 @@ impl ggez::event::EventHandler for MainState {
     fn focus_event(&mut self, ctx: &mut Context, gained: bool) {
         self.focused = gained;
         if gained {
-            if let Err(err) = config::load(ctx) {
+            // Replays and other players only match the config they started with
+            let networked = self.host.is_some() || self.client.is_some() || self.session.is_some();
+            if networked || self.recorder.is_some() || self.replay.is_some() {
+                println!("Not reloading the config during a recorded or networked game");
+            } else if let Err(err) = config::load(ctx) {
                 println!("Config error: {}", err);
             }
             match Images::new(ctx) {
@@ -475,9 +709,149 @@ impl ggez::event::EventHandler for MainState {
     }
 }
 
+struct Args {
+    seed: Option<u64>,
+    record: Option<String>,
+    replay: Option<String>,
+    host: Option<u16>,
+    connect: Option<String>,
+    peer: Option<rollback::Options>,
+    edit: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        seed: None,
+        record: None,
+        replay: None,
+        host: None,
+        connect: None,
+        peer: None,
+        edit: false,
+    };
+    let mut port = 0;
+    let mut slot = 0;
+    let mut input_delay = 2;
+    let mut sim_latency = 0;
+    let mut sim_loss = 0.0;
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let seed = iter.next().ok_or("--seed needs a value")?;
+                let seed = seed
+                    .parse()
+                    .map_err(|err| format!("Invalid seed '{}': {}", seed, err))?;
+                args.seed = Some(seed);
+            }
+            "--record" => {
+                args.record = Some(iter.next().ok_or("--record needs a file")?);
+            }
+            "--replay" => {
+                args.replay = Some(iter.next().ok_or("--replay needs a file")?);
+            }
+            "--host" => {
+                let port = iter.next().ok_or("--host needs a port")?;
+                let port = port
+                    .parse()
+                    .map_err(|err| format!("Invalid port '{}': {}", port, err))?;
+                args.host = Some(port);
+            }
+            "--connect" => {
+                args.connect = Some(iter.next().ok_or("--connect needs an address")?);
+            }
+            "--peer" => {
+                let remote = iter.next().ok_or("--peer needs an address")?;
+                args.peer = Some(rollback::Options {
+                    local_port: 0,
+                    remote,
+                    slot: 0,
+                    input_delay: 0,
+                    sim_latency: Duration::from_millis(0),
+                    sim_loss: 0.0,
+                });
+            }
+            "--edit" => args.edit = true,
+            "--port" => port = parse_value(&mut iter, "--port")?,
+            "--slot" => slot = parse_value(&mut iter, "--slot")?,
+            "--input-delay" => input_delay = parse_value(&mut iter, "--input-delay")?,
+            "--sim-latency" => sim_latency = parse_value(&mut iter, "--sim-latency")?,
+            "--sim-loss" => sim_loss = parse_value(&mut iter, "--sim-loss")?,
+            _ => return Err(format!("Unknown argument '{}'", arg)),
+        }
+    }
+
+    if args.edit {
+        let networked = args.host.is_some() || args.connect.is_some() || args.peer.is_some();
+        if networked || args.record.is_some() || args.replay.is_some() {
+            return Err("--edit can't be used with other modes".into());
+        }
+    }
+
+    if args.replay.is_some() && (args.host.is_some() || args.connect.is_some()) {
+        return Err("--replay can't be used with --host or --connect".into());
+    }
+
+    if let Some(peer) = &mut args.peer {
+        if args.host.is_some() || args.connect.is_some() {
+            return Err("--peer can't be used with --host or --connect".into());
+        }
+        if args.record.is_some() || args.replay.is_some() {
+            return Err("--peer can't be used with --record or --replay".into());
+        }
+        if slot > 1 {
+            return Err("--slot must be 0 or 1".into());
+        }
+        if sim_loss < 0.0 || sim_loss >= 1.0 {
+            return Err("--sim-loss must be at least 0 and less than 1".into());
+        }
+        peer.local_port = port;
+        peer.slot = slot;
+        peer.input_delay = input_delay;
+        peer.sim_latency = Duration::from_millis(sim_latency);
+        peer.sim_loss = sim_loss;
+    }
+    Ok(args)
+}
+
+fn resource_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources")
+}
+
+/// The modules file on disk, for the editor to save to
+fn modules_file() -> PathBuf {
+    resource_dir().join(MODULES_PATH.trim_start_matches('/'))
+}
+
+fn parse_value<T>(iter: &mut impl Iterator<Item = String>, name: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = iter.next().ok_or(format!("{} needs a value", name))?;
+    value
+        .parse()
+        .map_err(|err| format!("Invalid value '{}' for {}: {}", value, name, err))
+}
+
 fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            println!("{}", err);
+            println!(
+                "Usage: fall [--seed <number>] [--record <file>] [--replay <file>] \
+                 [--host <port> | --connect <address:port>]\n       \
+                 fall --peer <address:port> [--port <port>] [--slot <0|1>] [--input-delay <ticks>] \
+                 [--sim-latency <ms>] [--sim-loss <0-1>]\n       \
+                 fall --edit"
+            );
+            std::process::exit(1);
+        }
+    };
+
     let ctx = &mut ContextBuilder::new("fall", "acgames")
-        .add_resource_path(path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources"))
+        .add_resource_path(resource_dir())
         .window_setup(WindowSetup {
             title: "Fall".into(),
             ..Default::default()
@@ -497,7 +871,7 @@ fn main() {
         .unwrap();
     ctx.gamepad_context = ggez::input::GamepadContext::new(&ctx.sdl_context).unwrap();
 
-    let state = &mut MainState::new(ctx).unwrap();
+    let state = &mut MainState::new(ctx, &args).unwrap();
     if let Err(e) = ggez::event::run(ctx, state) {
         println!("Error encountered: {}", e);
     }
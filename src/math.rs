@@ -1,4 +1,4 @@
-use ggez::graphics::{Point2, Rect};
+use ggez::graphics::{Point2, Rect, Vector2};
 
 pub fn clamp(lower: f32, upper: f32, n: f32) -> f32 {
     if upper < n {
@@ -18,3 +18,15 @@ pub fn rect_from_point(point: Point2, w: f32, h: f32) -> Rect {
         h,
     }
 }
+
+/// The area a rect passes over while moving by `motion`
+pub fn swept_rect(rect: Rect, motion: Vector2) -> Rect {
+    let x = rect.x.min(rect.x + motion.x);
+    let y = rect.y.min(rect.y + motion.y);
+    Rect {
+        x,
+        y,
+        w: rect.w + motion.x.abs(),
+        h: rect.h + motion.y.abs(),
+    }
+}
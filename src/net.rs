@@ -0,0 +1,757 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use ggez::graphics::{Point2, Vector2};
+
+use crate::bullet::Bullet;
+use crate::codec::{self, Reader};
+use crate::grid::{self, Grid, GridState, Tile};
+use crate::pickup::{Pickup, PickupKind};
+use crate::player::{ControlState, Controls, Player, Team};
+use crate::simulation::{MenuPhase, Simulation};
+use crate::sound::SoundEffect;
+
+const MAGIC: &[u8; 4] = b"FALL";
+const VERSION: u8 = 5;
+
+// Client to host
+const INPUTS: u8 = 0;
+const JOIN: u8 = 1;
+// Host to client
+const WELCOME: u8 = 2;
+const FULL: u8 = 3;
+const SNAPSHOT: u8 = 4;
+const MISMATCH: u8 = 5;
+
+/// Peers that haven't sent anything for this long are dropped
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How often an unanswered join request is sent again, joins give up after `TIMEOUT`
+const JOIN_RESEND: Duration = Duration::from_millis(250);
+const MAX_PACKET: usize = 65536;
+
+const SOUNDS: &[SoundEffect] = &[
+    SoundEffect::Jump,
+    SoundEffect::SecondJump,
+    SoundEffect::Land,
+    SoundEffect::Shoot,
+    SoundEffect::BreakBlock,
+    SoundEffect::DamageBlock,
+    SoundEffect::DamageBlockMore,
+    SoundEffect::GridFallOffscreen,
+    SoundEffect::GridLand,
+    SoundEffect::GridLandBottom,
+    SoundEffect::Pickup,
+    SoundEffect::Dash,
+    SoundEffect::Repair,
+];
+
+const PICKUP_KINDS: &[PickupKind] = &[
+    PickupKind::Health,
+    PickupKind::Life,
+    PickupKind::RapidFire,
+    PickupKind::Shield,
+    PickupKind::Weapon,
+];
+
+pub enum HostEvent {
+    Join { addr: SocketAddr, request: u8 },
+    Input(usize, ControlState),
+    Left(usize),
+}
+
+struct Peer {
+    addr: SocketAddr,
+    last_heard: Instant,
+}
+
+/// Runs the authoritative simulation, taking inputs from remote players and
+/// sending everyone snapshots of the result.
+pub struct Host {
+    socket: UdpSocket,
+    peers: Vec<Peer>,
+    /// Who each slot was given to, and the join request that asked for it
+    slots: [Option<(SocketAddr, u8)>; 4],
+    /// Players can only join if their config and modules match ours
+    fingerprint: u64,
+    tick: u32,
+    buffer: Vec<u8>,
+}
+
+impl Host {
+    pub fn bind(port: u16, fingerprint: u64) -> io::Result<Host> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_nonblocking(true)?;
+        println!("Hosting on port {}", socket.local_addr()?.port());
+        Ok(Host {
+            socket,
+            peers: Vec::new(),
+            slots: [None; 4],
+            fingerprint,
+            tick: 0,
+            buffer: Vec::with_capacity(4096),
+        })
+    }
+
+    pub fn poll(&mut self, out: &mut Vec<HostEvent>) {
+        let mut packet = [0; MAX_PACKET];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut packet) {
+                Ok(x) => x,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    println!("Network error: {}", err);
+                    break;
+                }
+            };
+            if let Err(err) = self.handle(addr, &packet[..len], out) {
+                println!("Bad packet from {}: {}", addr, err);
+            }
+        }
+
+        let now = Instant::now();
+        let slots = &mut self.slots;
+        self.peers.retain(|peer| {
+            if now.duration_since(peer.last_heard) < TIMEOUT {
+                return true;
+            }
+            println!("{} timed out", peer.addr);
+            for (i, slot) in slots.iter_mut().enumerate() {
+                if slot.map(|x| x.0) == Some(peer.addr) {
+                    *slot = None;
+                    out.push(HostEvent::Left(i));
+                }
+            }
+            false
+        });
+    }
+
+    fn handle(
+        &mut self,
+        addr: SocketAddr,
+        packet: &[u8],
+        out: &mut Vec<HostEvent>,
+    ) -> io::Result<()> {
+        let mut reader = read_header(packet)?;
+        match self.peers.iter_mut().find(|peer| peer.addr == addr) {
+            Some(peer) => peer.last_heard = Instant::now(),
+            None => {
+                println!("{} connected", addr);
+                self.peers.push(Peer {
+                    addr,
+                    last_heard: Instant::now(),
+                });
+            }
+        }
+
+        match reader.u8()? {
+            INPUTS => {
+                let count = reader.u8()?;
+                for _ in 0..count {
+                    let slot = reader.slot()?;
+                    let state = reader.control_state()?;
+                    if self.slots[slot].map(|x| x.0) == Some(addr) {
+                        out.push(HostEvent::Input(slot, state));
+                    }
+                }
+            }
+            JOIN => {
+                // Clients keep asking until they hear back, so the same request
+                // can arrive again after it was answered or while it's being handled
+                let request = reader.u8()?;
+                if reader.u64()? != self.fingerprint {
+                    println!("{} has a different config or modules file", addr);
+                    start_packet(&mut self.buffer, MISMATCH);
+                    codec::put_u8(&mut self.buffer, request);
+                    send(&self.socket, &self.buffer, addr);
+                    return Ok(());
+                }
+                if let Some(slot) = self.slots.iter().position(|x| *x == Some((addr, request))) {
+                    self.welcome(addr, request, slot);
+                    return Ok(());
+                }
+                let duplicate = out.iter().any(|event| match event {
+                    HostEvent::Join {
+                        addr: a,
+                        request: r,
+                    } => *a == addr && *r == request,
+                    _ => false,
+                });
+                if !duplicate {
+                    out.push(HostEvent::Join { addr, request });
+                }
+            }
+            tag => return Err(codec::invalid_data(format!("Unknown packet {}", tag))),
+        }
+        Ok(())
+    }
+
+    /// Give a remote player the slot they asked for
+    pub fn accept(&mut self, addr: SocketAddr, request: u8, slot: usize) {
+        self.slots[slot] = Some((addr, request));
+        self.welcome(addr, request, slot);
+    }
+
+    fn welcome(&mut self, addr: SocketAddr, request: u8, slot: usize) {
+        start_packet(&mut self.buffer, WELCOME);
+        codec::put_u8(&mut self.buffer, request);
+        codec::put_u8(&mut self.buffer, slot as u8);
+        codec::put_u32(&mut self.buffer, self.tick);
+        codec::put_u64(&mut self.buffer, self.fingerprint);
+        send(&self.socket, &self.buffer, addr);
+    }
+
+    pub fn reject(&mut self, addr: SocketAddr, request: u8) {
+        start_packet(&mut self.buffer, FULL);
+        codec::put_u8(&mut self.buffer, request);
+        send(&self.socket, &self.buffer, addr);
+    }
+
+    /// Stop taking input for a slot whose player has left
+    pub fn release(&mut self, slot: usize) {
+        self.slots[slot] = None;
+    }
+
+    pub fn broadcast(&mut self, sim: &Simulation, sounds: &[SoundEffect]) {
+        self.tick += 1;
+        start_packet(&mut self.buffer, SNAPSHOT);
+        put_snapshot(&mut self.buffer, self.tick, sim, sounds);
+        for peer in &self.peers {
+            send(&self.socket, &self.buffer, peer.addr);
+        }
+    }
+}
+
+pub enum ClientEvent {
+    Joined(usize, Controls),
+    Left(usize),
+    Sound(SoundEffect),
+}
+
+/// A join request the host hasn't answered yet
+struct PendingJoin {
+    request: u8,
+    controls: Controls,
+    asked: Instant,
+    last_sent: Instant,
+}
+
+/// Sends local inputs to a host and mirrors the host's simulation
+pub struct Client {
+    socket: UdpSocket,
+    host: SocketAddr,
+    last_heard: Instant,
+    connected: bool,
+    next_request: u8,
+    pending: Vec<PendingJoin>,
+    // The host tick at which each of our local players joined
+    owned: [Option<u32>; 4],
+    fingerprint: u64,
+    tick: u32,
+    buffer: Vec<u8>,
+}
+
+impl Client {
+    pub fn connect(host: &str, fingerprint: u64) -> io::Result<Client> {
+        let host = host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| codec::invalid_data(format!("Could not resolve '{}'", host)))?;
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_nonblocking(true)?;
+        Ok(Client {
+            socket,
+            host,
+            last_heard: Instant::now(),
+            connected: false,
+            next_request: 0,
+            pending: Vec::new(),
+            owned: [None; 4],
+            fingerprint,
+            tick: 0,
+            buffer: Vec::with_capacity(64),
+        })
+    }
+
+    /// Ask the host for a slot for a new local player
+    pub fn join(&mut self, controls: Controls) {
+        let request = self.next_request;
+        self.next_request = self.next_request.wrapping_add(1);
+        let now = Instant::now();
+        self.pending.push(PendingJoin {
+            request,
+            controls,
+            asked: now,
+            last_sent: now,
+        });
+        self.send_join(request);
+    }
+
+    fn send_join(&mut self, request: u8) {
+        start_packet(&mut self.buffer, JOIN);
+        codec::put_u8(&mut self.buffer, request);
+        codec::put_u64(&mut self.buffer, self.fingerprint);
+        send(&self.socket, &self.buffer, self.host);
+    }
+
+    /// Send the state of every local player, this doubles as a keep-alive
+    pub fn send_inputs(&mut self, inputs: &[ControlState]) {
+        start_packet(&mut self.buffer, INPUTS);
+        let count = self.owned.iter().filter(|x| x.is_some()).count();
+        codec::put_u8(&mut self.buffer, count as u8);
+        for (slot, input) in inputs.iter().enumerate() {
+            if self.owned[slot].is_some() {
+                codec::put_u8(&mut self.buffer, slot as u8);
+                codec::put_control_state(&mut self.buffer, input);
+            }
+        }
+        send(&self.socket, &self.buffer, self.host);
+    }
+
+    pub fn poll(&mut self, sim: &mut Simulation, out: &mut Vec<ClientEvent>) {
+        let mut packet = [0; MAX_PACKET];
+        loop {
+            let len = match self.socket.recv_from(&mut packet) {
+                Ok((len, addr)) if addr == self.host => len,
+                Ok(_) => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    println!("Network error: {}", err);
+                    break;
+                }
+            };
+            if let Err(err) = self.handle(&packet[..len], sim, out) {
+                println!("Bad packet from host: {}", err);
+            }
+        }
+
+        if self.connected && self.last_heard.elapsed() > TIMEOUT {
+            println!("Lost connection to {}", self.host);
+            self.connected = false;
+        }
+
+        // Either packet of a join can get lost, keep asking until the host answers
+        let host = self.host;
+        self.pending.retain(|join| {
+            let waiting = join.asked.elapsed() < TIMEOUT;
+            if !waiting {
+                println!("{} didn't answer the join request", host);
+            }
+            waiting
+        });
+        for i in 0..self.pending.len() {
+            if self.pending[i].last_sent.elapsed() >= JOIN_RESEND {
+                self.pending[i].last_sent = Instant::now();
+                let request = self.pending[i].request;
+                self.send_join(request);
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        packet: &[u8],
+        sim: &mut Simulation,
+        out: &mut Vec<ClientEvent>,
+    ) -> io::Result<()> {
+        let mut reader = read_header(packet)?;
+        self.last_heard = Instant::now();
+        if !self.connected {
+            println!("Connected to {}", self.host);
+            self.connected = true;
+        }
+
+        match reader.u8()? {
+            WELCOME => {
+                let request = reader.u8()?;
+                let slot = reader.slot()?;
+                let tick = reader.u32()?;
+                if reader.u64()? != self.fingerprint {
+                    self.pending.retain(|x| x.request != request);
+                    println!("The host has a different config or modules file");
+                    return Ok(());
+                }
+                if let Some(i) = self.pending.iter().position(|x| x.request == request) {
+                    let join = self.pending.remove(i);
+                    self.owned[slot] = Some(tick);
+                    out.push(ClientEvent::Joined(slot, join.controls));
+                }
+            }
+            FULL => {
+                let request = reader.u8()?;
+                self.pending.retain(|x| x.request != request);
+                println!("The game is full");
+            }
+            MISMATCH => {
+                let request = reader.u8()?;
+                self.pending.retain(|x| x.request != request);
+                println!("The host has a different config or modules file");
+            }
+            SNAPSHOT => {
+                let tick = reader.u32()?;
+                // Snapshots can arrive out of order, only the newest one matters
+                if tick <= self.tick {
+                    return Ok(());
+                }
+                self.tick = tick;
+                read_snapshot(&mut reader, sim, out)?;
+                for (slot, owned) in self.owned.iter_mut().enumerate() {
+                    if let Some(joined) = *owned {
+                        if tick > joined && sim.players[slot].is_none() {
+                            *owned = None;
+                            out.push(ClientEvent::Left(slot));
+                        }
+                    }
+                }
+            }
+            tag => return Err(codec::invalid_data(format!("Unknown packet {}", tag))),
+        }
+        Ok(())
+    }
+}
+
+fn start_packet(buffer: &mut Vec<u8>, tag: u8) {
+    buffer.clear();
+    buffer.extend_from_slice(MAGIC);
+    codec::put_u8(buffer, VERSION);
+    codec::put_u8(buffer, tag);
+}
+
+fn read_header(packet: &[u8]) -> io::Result<Reader<'_>> {
+    let mut reader = Reader::new(packet);
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(codec::invalid_data("Not a game packet".into()));
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(codec::invalid_data(format!(
+            "Unsupported protocol version {} (expected {})",
+            version, VERSION
+        )));
+    }
+    Ok(reader)
+}
+
+fn send(socket: &UdpSocket, packet: &[u8], addr: SocketAddr) {
+    if let Err(err) = socket.send_to(packet, addr) {
+        println!("Could not send to {}: {}", addr, err);
+    }
+}
+
+fn put_snapshot(out: &mut Vec<u8>, tick: u32, sim: &Simulation, sounds: &[SoundEffect]) {
+    codec::put_u32(out, tick);
+    codec::put_u8(out, sim.in_menu as u8);
+    codec::put_u8(out, sim.menu_phase as u8);
+
+    codec::put_u8(out, sounds.len() as u8);
+    for sound in sounds {
+        let code = SOUNDS.iter().position(|x| x == sound).unwrap();
+        codec::put_u8(out, code as u8);
+    }
+
+    codec::put_u8(out, sim.grids.len() as u8);
+    for grid in &sim.grids {
+        codec::put_f32(out, grid.world_offset.y);
+        codec::put_f32(out, grid.vel.y);
+        codec::put_u8(out, (grid.state == GridState::Dead) as u8);
+        for row in grid.module.iter() {
+            for tile in row.iter() {
+                out.extend_from_slice(&tile.encode());
+            }
+        }
+        codec::put_u8(out, grid.pickups.len() as u8);
+        for pickup in &grid.pickups {
+            let code = PICKUP_KINDS.iter().position(|&x| x == pickup.kind).unwrap();
+            codec::put_u8(out, code as u8);
+            codec::put_u8(out, pickup.weapon as u8);
+            codec::put_u8(out, pickup.x as u8);
+            codec::put_u8(out, pickup.y as u8);
+        }
+    }
+
+    for player in &sim.players {
+        let player = match player {
+            Some(player) => player,
+            None => {
+                codec::put_u8(out, 0);
+                continue;
+            }
+        };
+        codec::put_u8(out, 1);
+        let (pos, vel) = (player.pos(), player.vel());
+        codec::put_f32(out, pos.x);
+        codec::put_f32(out, pos.y);
+        codec::put_f32(out, vel.x);
+        codec::put_f32(out, vel.y);
+        codec::put_f32(out, player.facing);
+        codec::put_u8(out, player.health());
+        codec::put_u8(out, player.lives);
+        codec::put_u8(out, player.alive as u8);
+        codec::put_u8(out, player.ready as u8);
+        codec::put_f32(out, player.invulnerable);
+        codec::put_f32(out, player.shield);
+        codec::put_u8(out, player.weapon as u8);
+    }
+
+    codec::put_u32(out, sim.bullets.len() as u32);
+    for bullet in &sim.bullets {
+        codec::put_f32(out, bullet.pos.x);
+        codec::put_f32(out, bullet.pos.y);
+        codec::put_f32(out, bullet.vel.x);
+        codec::put_f32(out, bullet.vel.y);
+        codec::put_u8(out, bullet.team.0);
+        codec::put_u8(out, bullet.weapon as u8);
+    }
+}
+
+fn read_snapshot(
+    reader: &mut Reader,
+    sim: &mut Simulation,
+    out: &mut Vec<ClientEvent>,
+) -> io::Result<()> {
+    sim.in_menu = reader.u8()? != 0;
+    sim.menu_phase = match reader.u8()? {
+        0 => MenuPhase::InMenu,
+        1 => MenuPhase::DropMenu,
+        2 => MenuPhase::InGame,
+        phase => return Err(codec::invalid_data(format!("Unknown menu phase {}", phase))),
+    };
+
+    for _ in 0..reader.u8()? {
+        let sound = *SOUNDS
+            .get(reader.u8()? as usize)
+            .ok_or_else(|| codec::invalid_data("Unknown sound".into()))?;
+        out.push(ClientEvent::Sound(sound));
+    }
+
+    let grid_count = reader.u8()?;
+    sim.grids.clear();
+    for _ in 0..grid_count {
+        let height = reader.f32()?;
+        let vel = reader.f32()?;
+        let dead = reader.u8()? != 0;
+        let mut module = [[Tile::Air; grid::GRID_WIDTH]; grid::GRID_HEIGHT];
+        for row in module.iter_mut() {
+            for tile in row.iter_mut() {
+                let bytes = reader.bytes(2)?;
+                *tile = Tile::decode([bytes[0], bytes[1]])
+                    .ok_or_else(|| codec::invalid_data("Unknown tile".into()))?;
+            }
+        }
+        let mut grid = Grid::new_from_module(height, module);
+        for _ in 0..reader.u8()? {
+            let kind = *PICKUP_KINDS
+                .get(reader.u8()? as usize)
+                .ok_or_else(|| codec::invalid_data("Unknown pickup".into()))?;
+            let weapon = reader.u8()? as usize;
+            let (x, y) = (reader.u8()? as usize, reader.u8()? as usize);
+            if x >= grid::GRID_WIDTH || y >= grid::GRID_HEIGHT {
+                return Err(codec::invalid_data("Pickup outside of its grid".into()));
+            }
+            grid.pickups.push(Pickup { kind, weapon, x, y });
+        }
+        grid.vel = Vector2::new(0.0, vel);
+        if dead {
+            grid.state = GridState::Dead;
+        }
+        sim.grids.push(grid);
+    }
+
+    for (i, slot) in sim.players.iter_mut().enumerate() {
+        if reader.u8()? == 0 {
+            *slot = None;
+            continue;
+        }
+        let pos = Point2::new(reader.f32()?, reader.f32()?);
+        let vel = Vector2::new(reader.f32()?, reader.f32()?);
+        let player = slot.get_or_insert_with(|| Player::new(Team(i as u8)));
+        player.set_physics(pos, vel);
+        player.facing = reader.f32()?;
+        player.set_health(reader.u8()?);
+        player.lives = reader.u8()?;
+        player.alive = reader.u8()? != 0;
+        player.ready = reader.u8()? != 0;
+        player.invulnerable = reader.f32()?;
+        player.shield = reader.f32()?;
+        player.weapon = reader.u8()? as usize;
+    }
+
+    let bullet_count = reader.u32()?;
+    sim.bullets.clear();
+    for _ in 0..bullet_count {
+        let pos = Point2::new(reader.f32()?, reader.f32()?);
+        let vel = Vector2::new(reader.f32()?, reader.f32()?);
+        let team = Team(reader.u8()?);
+        let weapon = reader.u8()? as usize;
+        sim.bullets.push(Bullet::new(pos, vel, team, weapon));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use ggez::event::Keycode;
+
+    use super::*;
+    use crate::config::GRID;
+    use crate::player::{Axis, Button};
+    use crate::simulation::tests::{load_config, test_match, test_simulation};
+
+    fn keyboard_controls() -> Controls {
+        let key = Button::Keyboard;
+        Controls {
+            lr: Axis::Buttons(key(Keycode::A), key(Keycode::D)),
+            aim: Axis::Buttons(key(Keycode::S), key(Keycode::W)),
+            jump: key(Keycode::W),
+            shoot: key(Keycode::Tab),
+            repair: key(Keycode::Q),
+            dash: key(Keycode::LShift),
+        }
+    }
+
+    /// Keep polling until `f` gives something back, packets over loopback are quick
+    fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> T {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(2) {
+            if let Some(x) = f() {
+                return x;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("Timed out");
+    }
+
+    const FINGERPRINT: u64 = 0x0123_4567_89ab_cdef;
+
+    fn connect(fingerprint: u64) -> (Host, Client) {
+        let host = Host::bind(0, FINGERPRINT).unwrap();
+        let port = host.socket.local_addr().unwrap().port();
+        let client = Client::connect(&format!("127.0.0.1:{}", port), fingerprint).unwrap();
+        (host, client)
+    }
+
+    #[test]
+    fn lost_welcomes_get_resent_without_a_second_slot() {
+        let (mut host, mut client) = connect(FINGERPRINT);
+        let mut sim = test_simulation(3);
+        client.join(keyboard_controls());
+
+        let (addr, request) = wait_for(|| {
+            let mut events = Vec::new();
+            host.poll(&mut events);
+            events.into_iter().find_map(|event| match event {
+                HostEvent::Join { addr, request } => Some((addr, request)),
+                _ => None,
+            })
+        });
+        host.accept(addr, request, 2);
+
+        // Lose the welcome
+        let mut packet = [0; MAX_PACKET];
+        wait_for(|| client.socket.recv_from(&mut packet).ok());
+
+        thread::sleep(JOIN_RESEND);
+        let mut events = Vec::new();
+        client.poll(&mut sim, &mut events);
+        assert!(events.is_empty());
+
+        let slot = wait_for(|| {
+            let mut joins = Vec::new();
+            host.poll(&mut joins);
+            assert!(joins.is_empty(), "Asking again shouldn't take a slot");
+            let mut events = Vec::new();
+            client.poll(&mut sim, &mut events);
+            events.into_iter().find_map(|event| match event {
+                ClientEvent::Joined(slot, _) => Some(slot),
+                _ => None,
+            })
+        });
+        assert_eq!(slot, 2);
+        assert!(client.pending.is_empty());
+    }
+
+    #[test]
+    fn tiles_off_the_wire_stay_in_range() {
+        load_config();
+        for &c in Tile::CHARS {
+            let tile = Tile::from_char(c).unwrap();
+            assert_eq!(Tile::decode(tile.encode()), Some(tile));
+        }
+        let max = GRID.tile_max_health;
+        assert_eq!(Tile::decode([1, 255]), Some(Tile::Solid(max)));
+        assert_eq!(Tile::decode([2, 3]), None);
+    }
+
+    #[test]
+    fn mismatched_peers_are_refused() {
+        let (mut host, mut client) = connect(FINGERPRINT + 1);
+        let mut sim = test_simulation(3);
+        client.join(keyboard_controls());
+
+        wait_for(|| {
+            let mut joins = Vec::new();
+            host.poll(&mut joins);
+            assert!(joins.is_empty(), "Mismatched peers shouldn't get a slot");
+            client.poll(&mut sim, &mut Vec::new());
+            Some(()).filter(|_| client.pending.is_empty())
+        });
+        assert!(client.owned.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn snapshots_mirror_the_host() {
+        let (mut host, mut client) = connect(FINGERPRINT);
+        let mut sim = test_match(3);
+        let mut inputs = [ControlState::default(); 4];
+        inputs[0].lr = -1.0;
+        inputs[0].facing = -1.0;
+        inputs[1].facing = 1.0;
+        for _ in 0..30 {
+            sim.step(&inputs);
+        }
+        let pickup = Pickup {
+            kind: PickupKind::Weapon,
+            weapon: 1,
+            x: 3,
+            y: 4,
+        };
+        sim.grids[0].pickups.push(pickup);
+        if let Some(player) = sim.players[1].as_mut() {
+            player.weapon = 1;
+            player.shield = 2.5;
+        }
+        host.peers.push(Peer {
+            addr: client.socket.local_addr().unwrap(),
+            last_heard: Instant::now(),
+        });
+        host.broadcast(&sim, &[]);
+
+        let mut mirror = test_simulation(4);
+        wait_for(|| {
+            let mut events = Vec::new();
+            client.poll(&mut mirror, &mut events);
+            Some(()).filter(|_| client.tick > 0)
+        });
+        assert_eq!(mirror.grids.len(), sim.grids.len());
+        for (mirrored, player) in mirror.players.iter().zip(&sim.players) {
+            let (mirrored, player) = (mirrored.as_ref(), player.as_ref());
+            assert_eq!(mirrored.map(Player::pos), player.map(Player::pos));
+            assert_eq!(mirrored.map(Player::vel), player.map(Player::vel));
+            assert_eq!(mirrored.map(|x| x.facing), player.map(|x| x.facing));
+            assert_eq!(mirrored.map(|x| x.weapon), player.map(|x| x.weapon));
+            assert_eq!(mirrored.map(|x| x.shield), player.map(|x| x.shield));
+            assert_eq!(
+                mirrored.map(|x| x.invulnerable),
+                player.map(|x| x.invulnerable)
+            );
+        }
+        for (mirrored, grid) in mirror.grids.iter().zip(&sim.grids) {
+            assert_eq!(mirrored.pickups, grid.pickups);
+        }
+        assert!(mirror.grids[0].pickups.contains(&pickup));
+    }
+}
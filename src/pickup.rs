@@ -0,0 +1,176 @@
+use ggez::{
+    graphics::{Color, DrawParam, Point2, Rect, Vector2},
+    Context, GameResult,
+};
+use rand::Rng;
+
+use crate::config::{PICKUPS, PLAYER};
+use crate::draw::{self, Batch};
+use crate::grid::{self, Grid, GridCoord, GridState, TILE_SIZE};
+use crate::images::Images;
+use crate::math;
+use crate::player::Player;
+use crate::simulation::{Event, GameRng};
+use crate::sound::SoundEffect;
+use crate::weapon;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PickupKind {
+    /// Back to full health
+    Health,
+    /// One more life, up to the most a player can have
+    Life,
+    /// Shoot much faster for a while
+    RapidFire,
+    /// Can't be hurt for a while
+    Shield,
+    /// Swap to another weapon
+    Weapon,
+}
+
+/// An entry in the table of pickups that can appear
+#[derive(Debug, Clone, Deserialize)]
+pub struct PickupChance {
+    pub kind: PickupKind,
+    pub weight: f32,
+    /// The name of the weapon a weapon pickup gives, any weapon if it's empty
+    #[serde(default)]
+    pub weapon: String,
+}
+
+/// An item waiting on a tile of a grid, so it falls along with it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Pickup {
+    pub kind: PickupKind,
+    /// The weapon given by weapon pickups
+    pub weapon: usize,
+    pub x: GridCoord,
+    pub y: GridCoord,
+}
+
+impl Pickup {
+    fn apply(&self, player: &mut Player) {
+        match self.kind {
+            PickupKind::Health => player.set_health(PLAYER.max_health),
+            PickupKind::Life => player.lives = (player.lives + 1).min(PLAYER.max_lives),
+            PickupKind::RapidFire => player.rapid_fire = PICKUPS.rapid_fire_seconds,
+            PickupKind::Shield => player.shield = PICKUPS.shield_seconds,
+            PickupKind::Weapon => player.weapon = self.weapon,
+        }
+    }
+}
+
+/// Pick an entry from the table by weight, giving its kind and weapon
+fn pick(rng: &mut GameRng) -> Option<(PickupKind, usize)> {
+    let total: f32 = PICKUPS.table.iter().map(|x| x.weight.max(0.0)).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut roll = rng.gen::<f32>() * total;
+    let chance = PICKUPS
+        .table
+        .iter()
+        .find(|chance| {
+            roll -= chance.weight.max(0.0);
+            roll < 0.0
+        })
+        .or_else(|| PICKUPS.table.last())?;
+    let weapon = match weapon::find(&chance.weapon) {
+        Some(weapon) => weapon,
+        None => rng.gen_range(0, weapon::count()),
+    };
+    Some((chance.kind, weapon))
+}
+
+/// Every so often put a new pickup on a free tile of a grid that's on screen
+pub fn spawn(grids: &mut [Grid], rng: &mut GameRng) {
+    let count: usize = grids.iter().map(|grid| grid.pickups.len()).sum();
+    // The rate comes straight from the config, keep it a valid chance
+    let chance = math::clamp(0.0, 1.0, PICKUPS.spawn_rate * crate::DT);
+    if count >= PICKUPS.max_pickups || !rng.gen_bool(f64::from(chance)) {
+        return;
+    }
+    let (kind, weapon) = match pick(rng) {
+        Some(picked) => picked,
+        None => return,
+    };
+
+    let mut candidates: Vec<usize> = (0..grids.len())
+        .filter(|&i| grids[i].state == GridState::Alive)
+        .filter(|&i| grids[i].world_offset.y < draw::WORLD_HEIGHT)
+        .collect();
+    rng.shuffle(&mut candidates);
+    for i in candidates {
+        let grid = &mut grids[i];
+        if let Some((x, y)) = grid::find_pickup_location(grid, rng) {
+            grid.pickups.push(Pickup { kind, weapon, x, y });
+            return;
+        }
+    }
+}
+
+/// Give players the pickups they're touching
+pub fn collect(grids: &mut [Grid], players: &mut [Option<Player>], events: &mut Vec<Event>) {
+    for player in players.iter_mut() {
+        let player = match player {
+            Some(player) if player.alive => player,
+            _ => continue,
+        };
+        let body = math::rect_from_point(player.pos(), PLAYER.width, PLAYER.height);
+        for grid in grids.iter_mut() {
+            let mut i = 0;
+            while i < grid.pickups.len() {
+                let pickup = grid.pickups[i];
+                if body.overlaps(&pickup_rect(grid, &pickup)) {
+                    pickup.apply(player);
+                    grid.pickups.remove(i);
+                    events.push(Event::Sound(SoundEffect::Pickup));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+fn pickup_rect(grid: &Grid, pickup: &Pickup) -> Rect {
+    math::rect_from_point(
+        grid.to_world_coords((pickup.x, pickup.y)),
+        TILE_SIZE,
+        TILE_SIZE,
+    )
+}
+
+pub fn draw(ctx: &mut Context, images: &Images, grid: &Grid) -> GameResult<()> {
+    let mut hearts = Batch::atlas(images.heart.clone(), 2, 1);
+    let mut lives = Batch::atlas(images.lives.clone(), 2, 1);
+    // The timed pickups are small tinted blocks
+    let mut blocks = Batch::atlas(images.tiles.clone(), 16, 16);
+    for pickup in &grid.pickups {
+        let dest = grid.to_world_coords((pickup.x, pickup.y));
+        let block = |color| DrawParam {
+            dest: dest + Vector2::new(0.25, 0.25),
+            color: Some(color),
+            scale: Point2::new(0.5, 0.5),
+            ..Default::default()
+        };
+        let icon = DrawParam {
+            dest,
+            ..Default::default()
+        };
+        match pickup.kind {
+            PickupKind::Health => hearts.add(0, icon),
+            PickupKind::Life => lives.add(0, icon),
+            PickupKind::RapidFire => blocks.add(0, block(Color::new(1.0, 0.8, 0.2, 1.0))),
+            PickupKind::Shield => blocks.add(0, block(Color::new(0.4, 1.0, 1.0, 1.0))),
+            PickupKind::Weapon => {
+                let [r, g, b, a] = weapon::get(pickup.weapon).color;
+                blocks.add(1, block(Color::new(r, g, b, a)));
+            }
+        }
+    }
+    hearts.draw(ctx, Default::default())?;
+    lives.draw(ctx, Default::default())?;
+    blocks.draw(ctx, Default::default())
+}
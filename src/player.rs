@@ -1,29 +1,41 @@
+use std::hash::{Hash, Hasher};
+
 use ggez::{
     event,
     graphics::{Color, DrawParam, Point2, Rect, Vector2},
     Context, GameResult,
 };
+use rand::Rng;
 
 use crate::bullet::Bullet;
-use crate::sound::{Sound, SoundEffect};
+use crate::simulation::Event;
+use crate::sound::SoundEffect;
 
 use crate::collide;
-use crate::config::PLAYER;
+use crate::config::{PICKUPS, PLAYER, SURFACES};
 use crate::draw;
-use crate::grid;
+use crate::grid::{self, Surface, Tile};
 use crate::images::Images;
 use crate::math;
+use crate::weapon;
+
+pub const GRAVITY: f32 = -20.0;
+const VERTICAL_DRAG: f32 = 0.995;
+const FRICTION: f32 = 0.95;
+const AIM_DEADZONE: f32 = 0.3;
+/// Times per second the player blinks while invulnerable, counting both on and off
+const BLINK_RATE: f32 = 16.0;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct Team(pub u8);
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Axis {
     Buttons(Button, Button),
     Analog(i32, event::Axis),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Button {
     Keyboard(event::Keycode),
     Controller(i32, event::Button),
@@ -32,55 +44,274 @@ pub enum Button {
 #[derive(Debug)]
 pub struct Controls {
     pub lr: Axis,
+    /// Up and down for aiming, analog sticks take the matching X axis too.
+    /// An analog movement stick aims while this one is left alone.
+    pub aim: Axis,
     pub jump: Button,
     pub shoot: Button,
+    pub repair: Button,
+    pub dash: Button,
 }
 
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct ControlState {
     pub lr: f32,
-    pub jump: bool,            // Updated every jump event (edge up and edge down)
-    pub this_jump_frame: bool, // Updated every frame
-    pub last_jump_frame: bool, // Updated every frame
+    pub jump: bool, // Updated every jump event (edge up and edge down)
     pub shoot: bool,
+    /// Fix up the grid the player is standing on
+    pub repair: bool,
+    pub dash: bool,
     pub l_pressed: bool,
     pub r_pressed: bool,
+    pub u_pressed: bool,
+    pub d_pressed: bool,
     pub facing: f32,
+    /// Direction to shoot in, the facing direction is used when it's close to zero
+    pub aim_x: f32,
+    pub aim_y: f32,
+    /// Where the aiming and movement sticks are pushed, with up being positive.
+    /// Only the aim worked out from them gets sent to other players.
+    pub aim_stick: [f32; 2],
+    pub move_stick: [f32; 2],
 }
 
-#[derive(Debug)]
+impl ControlState {
+    fn hash_state<H: Hasher>(&self, hasher: &mut H) {
+        let ControlState {
+            lr,
+            jump,
+            shoot,
+            repair,
+            dash,
+            l_pressed,
+            r_pressed,
+            u_pressed,
+            d_pressed,
+            facing,
+            aim_x,
+            aim_y,
+            // Only the local player knows where their sticks are
+            aim_stick: _,
+            move_stick: _,
+        } = self;
+        for value in &[*lr, *facing, *aim_x, *aim_y] {
+            value.to_bits().hash(hasher);
+        }
+        (jump, shoot, repair, dash).hash(hasher);
+        (l_pressed, r_pressed, u_pressed, d_pressed).hash(hasher);
+    }
+}
+
+impl Controls {
+    /// Update the control state for a press or release, returns whether the
+    /// button is one of these controls. Live play and replays both go through here.
+    pub fn button(&self, input: &mut ControlState, btn: &Button, pressed: bool) -> bool {
+        let mut found = false;
+        // Keyboards share the up key between jumping and aiming, pressing it
+        // while shooting aims up instead of jumping
+        let shared_up = match self.aim {
+            Axis::Buttons(_, ref u) => *u == self.jump,
+            Axis::Analog(..) => false,
+        };
+        let aiming_up = shared_up && input.shoot;
+        if *btn == self.jump && !(aiming_up && pressed) {
+            input.jump = pressed;
+            found = true;
+        }
+        if *btn == self.shoot {
+            input.shoot = pressed;
+            found = true;
+        }
+        if *btn == self.repair {
+            input.repair = pressed;
+            found = true;
+        }
+        if *btn == self.dash {
+            input.dash = pressed;
+            found = true;
+        }
+        if let Axis::Buttons(ref l, ref r) = self.lr {
+            if btn == l {
+                if pressed {
+                    input.facing = -1.0;
+                } else if input.r_pressed {
+                    input.facing = 1.0;
+                }
+                input.l_pressed = pressed;
+                found = true;
+            }
+            if btn == r {
+                if pressed {
+                    input.facing = 1.0;
+                } else if input.l_pressed {
+                    input.facing = -1.0;
+                }
+                input.r_pressed = pressed;
+                found = true;
+            }
+            input.lr = 0.0;
+            if input.l_pressed {
+                input.lr -= 1.0;
+            }
+            if input.r_pressed {
+                input.lr += 1.0;
+            }
+            // Keyboards aim along the way the player is walking
+            input.aim_x = input.lr;
+        }
+        if let Axis::Buttons(ref d, ref u) = self.aim {
+            if btn == d {
+                input.d_pressed = pressed;
+                found = true;
+            }
+            if btn == u {
+                input.u_pressed = pressed && (aiming_up || !shared_up);
+                found = true;
+            }
+            input.aim_y = 0.0;
+            if input.d_pressed {
+                input.aim_y -= 1.0;
+            }
+            if input.u_pressed {
+                input.aim_y += 1.0;
+            }
+        }
+        found
+    }
+
+    /// Update the control state for an analog stick moving to `value`
+    pub fn axis(&self, input: &mut ControlState, axis: &Axis, value: f32) {
+        if *axis == self.lr {
+            if value.abs() > 0.1 {
+                input.facing = value.signum();
+            }
+            input.lr = value;
+        }
+        if let Axis::Analog(aim_id, aim_axis) = self.aim {
+            // Stick Y points down, aiming goes up like the world
+            if *axis == self.aim {
+                input.aim_stick[1] = -value;
+            } else if stick_x(aim_axis).map(|x| Axis::Analog(aim_id, x)) == Some(*axis) {
+                input.aim_stick[0] = value;
+            }
+            if let Axis::Analog(move_id, move_axis) = self.lr {
+                if *axis == self.lr {
+                    input.move_stick[0] = value;
+                } else if stick_y(move_axis).map(|y| Axis::Analog(move_id, y)) == Some(*axis) {
+                    input.move_stick[1] = -value;
+                }
+            }
+
+            // A single joycon only has the one stick, so aim with the movement
+            // stick while the aiming stick is left alone
+            let [x, y] = input.aim_stick;
+            let [x, y] = if Vector2::new(x, y).norm() < AIM_DEADZONE {
+                input.move_stick
+            } else {
+                [x, y]
+            };
+            input.aim_x = x;
+            input.aim_y = y;
+        }
+    }
+}
+
+/// The horizontal axis of the stick that a vertical axis belongs to
+fn stick_x(y: event::Axis) -> Option<event::Axis> {
+    match y {
+        event::Axis::LeftY => Some(event::Axis::LeftX),
+        event::Axis::RightY => Some(event::Axis::RightX),
+        _ => None,
+    }
+}
+
+/// The vertical axis of the stick that a horizontal axis belongs to
+fn stick_y(x: event::Axis) -> Option<event::Axis> {
+    match x {
+        event::Axis::LeftX => Some(event::Axis::LeftY),
+        event::Axis::RightX => Some(event::Axis::RightY),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Player {
     pub team: Team,
-    pub controls: Controls,
     pub control_state: ControlState,
+    last_jump_frame: bool, // Updated every frame
+    last_dash_frame: bool,
+    last_facing_frame: f32,
+    /// The way the player is turned, -1 for left and 1 for right
+    pub facing: f32,
     pos: Point2,
     vel: Vector2,
     acc: Vector2,
     health: u8,
     pub lives: u8,
     pub cooldown: f32,
+    /// Seconds left before hazards, explosions and bullets can hurt the player again
+    pub invulnerable: f32,
+    /// Seconds left of having less control after getting hit
+    pub stun: f32,
+    /// Seconds left of the current dash
+    pub dash: f32,
+    /// Seconds until the player can dash again
+    pub dash_cooldown: f32,
+    /// Seconds of rapid fire left from a pickup
+    pub rapid_fire: f32,
+    /// Seconds left of not taking any damage from a pickup
+    pub shield: f32,
+    /// Index of the weapon in the config
+    pub weapon: usize,
     pub alive: bool,
     pub grounded: bool,
+    /// The tile the player is standing on, if they're grounded
+    ground: Option<Tile>,
     pub frames_since_grounded: u8,
+    /// Which side the player was pushed against a wall on, -1 for left, 1
+    /// for right and 0 when not touching one
+    wall: f32,
+    /// The side of the last wall jumped off since landing, 0 when there
+    /// hasn't been one
+    wall_jumped: f32,
     jump: JumpState,
+    /// Ticks left that a jump press waits for a jump to be available
+    jump_buffer: u8,
+    /// Rising from a jump that can still be cut short by letting go
+    jumping: bool,
     pub ready: bool,
 }
 
 impl Player {
-    pub fn new(team: Team, controls: Controls) -> Self {
+    pub fn new(team: Team) -> Self {
         Player {
             team,
-            controls,
             control_state: ControlState::default(),
+            last_jump_frame: false,
+            last_dash_frame: false,
+            last_facing_frame: 0.0,
+            facing: 0.0,
             pos: Point2::new(0.0, 0.0),
             vel: Vector2::new(0.0, 0.0),
             acc: Vector2::new(0.0, 0.0),
             health: PLAYER.max_health,
             lives: PLAYER.max_lives,
             cooldown: 0.0,
+            invulnerable: 0.0,
+            stun: 0.0,
+            dash: 0.0,
+            dash_cooldown: 0.0,
+            rapid_fire: 0.0,
+            shield: 0.0,
+            weapon: weapon::default_index(),
             alive: false,
             frames_since_grounded: 0,
+            wall: 0.0,
+            wall_jumped: 0.0,
+            jump_buffer: 0,
+            jumping: false,
             grounded: true,
+            ground: None,
             ready: false,
             jump: JumpState::Double,
         }
@@ -90,22 +321,104 @@ impl Player {
         self.health
     }
 
-    fn controls(&mut self) {
-        if let Axis::Buttons(..) = self.controls.lr {
-            self.control_state.lr = 0.0;
-            if self.control_state.l_pressed {
-                self.control_state.lr -= 1.0;
-            }
-            if self.control_state.r_pressed {
-                self.control_state.lr += 1.0;
-            }
+    pub fn pos(&self) -> Point2 {
+        self.pos
+    }
+
+    pub fn vel(&self) -> Vector2 {
+        self.vel
+    }
+
+    /// Overwrite the physics state, used when mirroring a remote simulation
+    pub fn set_physics(&mut self, pos: Point2, vel: Vector2) {
+        self.pos = pos;
+        self.vel = vel;
+    }
+
+    pub fn set_health(&mut self, health: u8) {
+        self.health = health;
+    }
+
+    /// Feed all of the player's state into a checksum. Every field is listed
+    /// so new ones can't be left out by accident.
+    pub fn hash_state<H: Hasher>(&self, hasher: &mut H) {
+        let Player {
+            team,
+            control_state,
+            last_jump_frame,
+            last_dash_frame,
+            last_facing_frame,
+            facing,
+            pos,
+            vel,
+            acc,
+            health,
+            lives,
+            cooldown,
+            invulnerable,
+            stun,
+            dash,
+            dash_cooldown,
+            rapid_fire,
+            shield,
+            weapon,
+            alive,
+            grounded,
+            ground,
+            frames_since_grounded,
+            wall,
+            wall_jumped,
+            jump,
+            jump_buffer,
+            jumping,
+            ready,
+        } = self;
+        control_state.hash_state(hasher);
+        for value in &[
+            *last_facing_frame,
+            *facing,
+            pos.x,
+            pos.y,
+            vel.x,
+            vel.y,
+            acc.x,
+            acc.y,
+            *cooldown,
+            *invulnerable,
+            *stun,
+            *dash,
+            *dash_cooldown,
+            *rapid_fire,
+            *shield,
+            *wall,
+            *wall_jumped,
+        ] {
+            value.to_bits().hash(hasher);
         }
+        (
+            team.0,
+            last_jump_frame,
+            last_dash_frame,
+            health,
+            lives,
+            weapon,
+            jump_buffer,
+            jumping,
+        )
+            .hash(hasher);
+        (alive, grounded, ground, frames_since_grounded, jump, ready).hash(hasher);
+    }
+
+    /// Does the player have a jump left?
+    pub fn can_jump(&self) -> bool {
+        self.jump != JumpState::None
     }
+
     /// Respawn the player in a grid, picked at random. Returns true if it succesfuly
     /// respawns the player, and false if it cannot find a place to spawn
     #[must_use]
-    pub fn respawn(&mut self, grid: &grid::Grid) -> bool {
-        let grid_coords = grid::find_spawn_location(grid.module);
+    pub fn respawn(&mut self, grid: &grid::Grid, rng: &mut impl Rng) -> bool {
+        let grid_coords = grid::find_spawn_location(grid.module, rng);
         if grid_coords == None {
             return false;
         }
@@ -116,80 +429,152 @@ impl Player {
         self.acc = Vector2::new(0.0, 0.0);
         self.alive = true;
         self.health = PLAYER.max_health;
+        self.invulnerable = 0.0;
+        self.stun = 0.0;
+        self.dash = 0.0;
+        self.dash_cooldown = 0.0;
+        self.wall = 0.0;
+        self.wall_jumped = 0.0;
+        self.jump_buffer = 0;
+        self.jumping = false;
+        self.rapid_fire = 0.0;
+        self.shield = 0.0;
         true
     }
 
-    pub fn update(&mut self, ctx: &mut Context, bullets: &mut Vec<Bullet>, sounds: &mut Sound) {
+    pub fn update(&mut self, bullets: &mut Vec<Bullet>, events: &mut Vec<Event>) {
         if !self.alive {
             return;
         }
 
-        self.control_state.last_jump_frame = self.control_state.this_jump_frame;
-        self.control_state.this_jump_frame = self.control_state.jump;
-        self.controls();
+        let last_jump_frame = self.last_jump_frame;
+        self.last_jump_frame = self.control_state.jump;
 
-        use self::JumpState::*;
-        // Want to jump (rising jump edge)
-        if !self.control_state.last_jump_frame && self.control_state.this_jump_frame {
-            self.jump = match self.jump {
-                Double => {
-                    self.acc.y = PLAYER.jump_power / crate::DT;
-                    self.grounded = false;
-                    sounds.play_sound(ctx, SoundEffect::Jump);
-                    Single
-                }
-                Single => {
-                    self.acc.y = PLAYER.second_jump_power / crate::DT;
-                    self.grounded = false;
-                    sounds.play_sound(ctx, SoundEffect::SecondJump);
-                    None
-                }
-                None => None,
-            }
+        // Only turn when the input changes, so holding towards a wall doesn't
+        // undo the turn from jumping off it
+        let last_facing_frame = self.last_facing_frame;
+        self.last_facing_frame = self.control_state.facing;
+        if self.control_state.facing != last_facing_frame && self.control_state.facing != 0.0 {
+            self.facing = self.control_state.facing;
         }
 
-        // Transition from air to grounded
-        if self.grounded && self.frames_since_grounded > 3 {
+        // Transition from air to grounded, straight away so that a buffered
+        // jump can use it on the tick the player lands
+        if self.grounded && self.frames_since_grounded > 0 {
             self.jump = JumpState::Double;
-            sounds.play_sound(ctx, SoundEffect::Land);
+            self.wall_jumped = 0.0;
+            // Skipping across a gap between tiles isn't worth a sound
+            if self.frames_since_grounded > 3 {
+                events.push(Event::Sound(SoundEffect::Land));
+            }
         }
 
-        // Transition from grounded to air
-        if !self.grounded && self.frames_since_grounded > 3 {
-            if self.jump == JumpState::Double {
-                self.jump = JumpState::Single;
+        // Transition from grounded to air, late jumps off a ledge still count
+        // as jumping from the ground for a few ticks
+        if !self.grounded
+            && self.frames_since_grounded > PLAYER.coyote_ticks
+            && self.jump == JumpState::Double
+        {
+            self.jump = JumpState::Single;
+        }
+
+        // Letting go of jump early cuts the jump short
+        if self.jumping && (!self.control_state.jump || self.vel.y <= 0.0) {
+            if self.vel.y > 0.0 {
+                self.vel.y *= PLAYER.jump_release_cut;
             }
+            self.jumping = false;
+        }
+
+        // Jump presses are remembered for a few ticks, so pressing it just
+        // before landing still jumps
+        if !last_jump_frame && self.control_state.jump {
+            self.jump_buffer = PLAYER.jump_buffer_ticks.saturating_add(1);
+        }
+        if self.jump_buffer > 0 {
+            self.jump_buffer -= 1;
+            if self.try_jump(events) {
+                self.jump_buffer = 0;
+                self.jumping = true;
+            }
+        }
+
+        let last_dash_frame = self.last_dash_frame;
+        self.last_dash_frame = self.control_state.dash;
+        if !last_dash_frame && self.control_state.dash && self.dash_cooldown <= 0.0 {
+            let dir = if self.control_state.lr != 0.0 {
+                self.control_state.lr.signum()
+            } else {
+                self.facing
+            };
+            self.vel = Vector2::new(dir * PLAYER.dash_speed, 0.0);
+            self.dash = PLAYER.dash_time;
+            self.dash_cooldown = PLAYER.dash_cooldown;
+            self.invulnerable = self.invulnerable.max(PLAYER.dash_invulnerable_time);
+            events.push(Event::Sound(SoundEffect::Dash));
         }
 
         if self.control_state.shoot && self.cooldown <= 0.0 {
-            bullets.push(Bullet::new(
-                self.pos + Vector2::new(PLAYER.width / 2.0, 0.0) + Vector2::new(
-                    self.control_state.facing * PLAYER.shoot_offset_x,
-                    PLAYER.shoot_offset_y,
-                ),
-                Vector2::new(self.control_state.facing * 30.0, 0.0),
-                self.team,
-            ));
-            self.cooldown = 0.3;
-            sounds.play_sound(ctx, SoundEffect::Shoot);
+            let weapon = weapon::get(self.weapon);
+            let facing = self.facing;
+            let origin = self.pos
+                + Vector2::new(PLAYER.width / 2.0, 0.0)
+                + Vector2::new(facing * PLAYER.shoot_offset_x, PLAYER.shoot_offset_y);
+            // Fan the bullets out evenly around the direction the player aims
+            let aim = self.aim();
+            let aim_angle = aim.y.atan2(aim.x);
+            let count = weapon.bullets.max(1);
+            for i in 0..count {
+                let angle =
+                    aim_angle + (i as f32 - (count - 1) as f32 / 2.0) * weapon.spread.to_radians();
+                let vel = Vector2::new(angle.cos(), angle.sin()) * weapon.speed;
+                bullets.push(Bullet::new(origin, vel, self.team, self.weapon));
+            }
+            self.cooldown = if self.rapid_fire > 0.0 {
+                PICKUPS.rapid_fire_cooldown.min(weapon.cooldown)
+            } else {
+                weapon.cooldown
+            };
+            events.push(Event::Sound(SoundEffect::Shoot));
         }
 
         self.acc.x += self.control_state.lr / crate::DT;
 
         if self.pos.y < -1.0 {
-            self.damage();
+            // Shields don't save players that fell out of the world
+            self.shield = 0.0;
+            self.damage(events);
         }
     }
 
-    pub fn fixed_update(&mut self, grids: &[grid::Grid]) {
+    pub fn fixed_update(&mut self, grids: &[grid::Grid], events: &mut Vec<Event>) {
         if !self.alive {
             return;
         }
 
         self.cooldown = 0.0f32.max(self.cooldown - crate::DT);
-        self.vel += crate::DT * self.acc;
-        self.vel.x *= 0.95;
-        self.vel.y *= 0.995;
+        self.invulnerable = 0.0f32.max(self.invulnerable - crate::DT);
+        self.stun = 0.0f32.max(self.stun - crate::DT);
+        self.dash = 0.0f32.max(self.dash - crate::DT);
+        self.dash_cooldown = 0.0f32.max(self.dash_cooldown - crate::DT);
+        self.rapid_fire = 0.0f32.max(self.rapid_fire - crate::DT);
+        self.shield = 0.0f32.max(self.shield - crate::DT);
+
+        // Ice makes it harder to both speed up and slow down
+        let (friction, mut control) = match self.ground.and_then(Tile::surface) {
+            Some(Surface::Ice) => (SURFACES.ice_friction, SURFACES.ice_control),
+            _ => (FRICTION, 1.0),
+        };
+        if self.stun > 0.0 {
+            control *= PLAYER.hit_stun_control;
+        }
+        self.acc.x *= control;
+        // Dashes go in a straight line at full speed
+        if self.dash <= 0.0 {
+            self.vel += crate::DT * self.acc;
+            self.vel.x *= friction;
+            self.vel.y *= VERTICAL_DRAG;
+        }
 
         // Collision resolution, this is done by move the player along the y-axis,
         // and moving them them back so that they do not collide with the wall,
@@ -200,9 +585,19 @@ impl Player {
         // Resolve Vertically
         next_pos.y += self.vel.y * crate::DT;
 
+        // Falling fast or a grid stopping suddenly under the player could carry
+        // them past a whole tile in one tick. Stop just inside the first tile in
+        // the way so the resolution below puts them on top of it.
+        let rect = math::rect_from_point(self.pos, PLAYER.width, PLAYER.height);
+        let velocity = Vector2::new(0.0, self.vel.y);
+        if let Some((hit, collider)) = collide::sweep_tiles(grids, rect, velocity) {
+            let travel = hit.time * crate::DT * (self.vel.y - collider.vel.y);
+            next_pos.y = self.pos.y + travel - hit.normal.y * collide::COLLISION_TOLERANCE;
+        }
+
         let next_rect = math::rect_from_point(next_pos, PLAYER.width, PLAYER.height);
         collide::get_overlapping_tiles(grids, next_rect, &mut colliders);
-        let (res_disp_y, res_vel_y) =
+        let (res_disp_y, res_vel_y, contact) =
             collide::resolve_colliders_vert(next_rect, self.vel, &colliders);
         next_pos.y += res_disp_y;
         self.vel = res_vel_y;
@@ -215,6 +610,17 @@ impl Player {
         // If the displacement was vertical that means we have been pushed up
         // out of the ground, which means we are probably grounded.
         self.grounded = res_disp_y > 0.0;
+        self.ground = if self.grounded { contact } else { None };
+
+        match self.ground.and_then(Tile::surface) {
+            Some(Surface::Bounce) => {
+                self.vel.y += SURFACES.bounce_impulse;
+                events.push(Event::Sound(SoundEffect::Jump));
+            }
+            Some(Surface::ConveyorLeft) => self.vel.x -= SURFACES.conveyor_push,
+            Some(Surface::ConveyorRight) => self.vel.x += SURFACES.conveyor_push,
+            Some(Surface::Ice) | None => (),
+        }
 
         // Resolve Horizontally
         next_pos.x += crate::DT * self.vel.x;
@@ -222,11 +628,25 @@ impl Player {
         let next_rect = math::rect_from_point(next_pos, PLAYER.width, PLAYER.height);
         colliders.clear();
         collide::get_overlapping_tiles(grids, next_rect, &mut colliders);
-        let (res_disp_x, res_vel_x) =
+        let (res_disp_x, res_vel_x, _) =
             collide::resolve_colliders_horiz(next_rect, self.vel, &colliders);
         next_pos.x += res_disp_x;
         self.vel = res_vel_x;
 
+        // Being pushed out of a tile sideways means there's a wall on the
+        // other side
+        self.wall = if res_disp_x < 0.0 {
+            1.0
+        } else if res_disp_x > 0.0 {
+            -1.0
+        } else {
+            0.0
+        };
+        let holding_wall = self.control_state.lr * self.wall > 0.0;
+        if !self.grounded && holding_wall && self.vel.y < -PLAYER.wall_slide_speed {
+            self.vel.y = -PLAYER.wall_slide_speed;
+        }
+
         self.pos = next_pos;
 
         // Don't let the player escape!
@@ -235,37 +655,171 @@ impl Player {
         }
         self.pos.x = math::clamp(0.0, draw::WORLD_WIDTH - PLAYER.width, self.pos.x);
         // Gravity
-        self.acc = Vector2::new(0.0, -20.0);
+        self.acc = Vector2::new(0.0, GRAVITY);
+
+        self.touch_hazards(grids, events);
+    }
+
+    /// Hurt the player and throw them away from the first hazard they're touching
+    fn touch_hazards(&mut self, grids: &[grid::Grid], events: &mut Vec<Event>) {
+        if self.invulnerable > 0.0 {
+            return;
+        }
+
+        // Collisions leave the player just outside of the tiles they hit, so
+        // look a little further out to find the ones they're touching
+        let margin = collide::COLLISION_TOLERANCE * 2.0;
+        let rect = math::rect_from_point(
+            self.pos - Vector2::new(margin, margin),
+            PLAYER.width + margin * 2.0,
+            PLAYER.height + margin * 2.0,
+        );
+        let center = self.center();
+        let mut tiles = Vec::with_capacity(6);
+        for grid in grids {
+            tiles.clear();
+            grid.overlapping_tiles(rect, &mut tiles);
+            for &(tile, x, y) in &tiles {
+                let hazard = match tile.hazard() {
+                    Some(hazard) => hazard,
+                    None => continue,
+                };
+                let half_tile = Vector2::new(grid::TILE_SIZE / 2.0, grid::TILE_SIZE / 2.0);
+                let away = center - (grid.to_world_coords((x, y)) + half_tile);
+                let dir = if away.norm() > 0.0 {
+                    away.normalize()
+                } else {
+                    Vector2::new(0.0, 1.0)
+                };
+                self.hurt(grid.vel + dir * hazard.info().knockback, events);
+                return;
+            }
+        }
     }
 
     pub fn draw(&self, ctx: &mut Context, images: &Images) -> GameResult<()> {
         if !self.alive {
             return Ok(());
         }
+        // Blink while invulnerable
+        if self.invulnerable > 0.0 && (self.invulnerable * BLINK_RATE) as u32 % 2 == 1 {
+            return Ok(());
+        }
         draw::draw_sprite(
             ctx,
             &images.player,
             DrawParam {
                 dest: self.pos,
-                color: Some(Color::new(1.0, 1.0, 1.0, 1.0)),
+                color: Some(if self.shield > 0.0 {
+                    Color::new(0.4, 1.0, 1.0, 1.0)
+                } else {
+                    Color::new(1.0, 1.0, 1.0, 1.0)
+                }),
                 ..Default::default()
             },
         )?;
         Ok(())
     }
 
-    pub fn kill(&mut self) {
+    pub fn kill(&mut self, events: &mut Vec<Event>) {
         assert_eq!(self.health, 0);
         if self.alive {
             self.lives = self.lives.saturating_sub(1);
+            events.push(Event::PlayerDied(self.team));
         }
         self.alive = false;
     }
 
-    pub fn damage(&mut self) {
+    pub fn damage(&mut self, events: &mut Vec<Event>) {
+        if self.shield > 0.0 {
+            return;
+        }
         self.health = self.health.saturating_sub(1);
         if self.health == 0 {
-            self.kill();
+            self.kill(events);
+        }
+    }
+
+    /// Damage the player and throw them with the given velocity, unless they
+    /// were hurt too recently. Returns whether they got hurt.
+    pub fn hurt(&mut self, knockback: Vector2, events: &mut Vec<Event>) -> bool {
+        if !self.alive || self.invulnerable > 0.0 {
+            return false;
+        }
+        self.vel = knockback;
+        self.invulnerable = PLAYER.invulnerable_time;
+        self.stun = PLAYER.hit_stun;
+        self.damage(events);
+        true
+    }
+
+    /// Damage the player for a bullet hit and push them along with `impulse`,
+    /// unless they were hurt too recently. Returns whether they got hurt.
+    pub fn shot(&mut self, impulse: Vector2, damage: u8, events: &mut Vec<Event>) -> bool {
+        if !self.alive || self.invulnerable > 0.0 {
+            return false;
+        }
+        self.vel += impulse;
+        self.invulnerable = PLAYER.hit_invulnerable_time;
+        self.stun = PLAYER.hit_stun;
+        for _ in 0..damage {
+            self.damage(events);
+        }
+        true
+    }
+
+    /// Use up a jump if there's one left. Returns whether the player jumped.
+    fn try_jump(&mut self, events: &mut Vec<Event>) -> bool {
+        use self::JumpState::*;
+        self.jump = match self.jump {
+            // Jumping off a wall gives back the second jump, but jumping off
+            // the same wall again uses one up so it can't be climbed forever
+            jump if !self.grounded
+                && self.wall != 0.0
+                && (self.wall != self.wall_jumped || jump != None) =>
+            {
+                self.acc.y = PLAYER.jump_power / crate::DT;
+                self.vel.x = -self.wall * PLAYER.wall_jump_push;
+                self.facing = -self.wall;
+                let same_wall = self.wall == self.wall_jumped;
+                self.wall_jumped = self.wall;
+                self.wall = 0.0;
+                events.push(Event::Sound(SoundEffect::Jump));
+                if same_wall && jump != Double {
+                    None
+                } else {
+                    Single
+                }
+            }
+            Double => {
+                self.acc.y = PLAYER.jump_power / crate::DT;
+                self.grounded = false;
+                events.push(Event::Sound(SoundEffect::Jump));
+                Single
+            }
+            Single => {
+                self.acc.y = PLAYER.second_jump_power / crate::DT;
+                self.grounded = false;
+                events.push(Event::Sound(SoundEffect::SecondJump));
+                None
+            }
+            None => return false,
+        };
+        true
+    }
+
+    pub fn center(&self) -> Point2 {
+        self.pos + Vector2::new(PLAYER.width / 2.0, PLAYER.height / 2.0)
+    }
+
+    /// Unit vector the player shoots along
+    fn aim(&self) -> Vector2 {
+        let state = &self.control_state;
+        let aim = Vector2::new(state.aim_x, state.aim_y);
+        if aim.norm() < AIM_DEADZONE {
+            Vector2::new(self.facing, 0.0)
+        } else {
+            aim.normalize()
         }
     }
 
@@ -279,9 +833,112 @@ impl Player {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 enum JumpState {
     Double,
     Single,
     None,
 }
+
+/// How high the player's feet get with a single jump, and with both jumps when
+/// the second one is used at the top of the first
+pub fn jump_heights() -> (f32, f32) {
+    let rise = |power: f32| {
+        let mut vel = power;
+        let mut height = 0.0;
+        while vel > 0.0 {
+            vel *= VERTICAL_DRAG;
+            height += vel * crate::DT;
+            vel += GRAVITY * crate::DT;
+        }
+        height
+    };
+    let single = rise(PLAYER.jump_power);
+    (single, single + rise(PLAYER.second_jump_power))
+}
+
+#[cfg(test)]
+mod tests {
+    use ggez::event::Keycode;
+
+    use super::*;
+    use crate::simulation::tests::load_config;
+
+    fn stick_controls(aim: event::Axis) -> Controls {
+        Controls {
+            lr: Axis::Analog(0, event::Axis::LeftX),
+            aim: Axis::Analog(0, aim),
+            jump: Button::Controller(0, event::Button::A),
+            shoot: Button::Controller(0, event::Button::B),
+            repair: Button::Controller(0, event::Button::X),
+            dash: Button::Controller(0, event::Button::Y),
+        }
+    }
+
+    #[test]
+    fn either_stick_aims() {
+        let controls = stick_controls(event::Axis::RightY);
+        let mut input = ControlState::default();
+        let mut push = |axis, value| {
+            controls.axis(&mut input, &Axis::Analog(0, axis), value);
+            (input.aim_x, input.aim_y)
+        };
+
+        // Only the movement stick, like a single joycon
+        push(event::Axis::LeftX, 0.5);
+        assert_eq!(push(event::Axis::LeftY, -1.0), (0.5, 1.0));
+
+        // The aiming stick takes over while it's pushed
+        assert_eq!(push(event::Axis::RightX, -1.0), (-1.0, 0.0));
+        assert_eq!(push(event::Axis::RightX, 0.0), (0.5, 1.0));
+    }
+
+    #[test]
+    fn keyboards_aim_up_while_shooting_without_jumping() {
+        let key = Button::Keyboard;
+        let controls = Controls {
+            lr: Axis::Buttons(key(Keycode::A), key(Keycode::D)),
+            aim: Axis::Buttons(key(Keycode::S), key(Keycode::W)),
+            jump: key(Keycode::W),
+            shoot: key(Keycode::Tab),
+            repair: key(Keycode::Q),
+            dash: key(Keycode::LShift),
+        };
+        let mut input = ControlState::default();
+
+        // Up on its own only jumps
+        controls.button(&mut input, &key(Keycode::W), true);
+        assert!(input.jump);
+        assert_eq!(input.aim_y, 0.0);
+        controls.button(&mut input, &key(Keycode::W), false);
+
+        // Holding shoot turns it into aiming
+        controls.button(&mut input, &key(Keycode::Tab), true);
+        controls.button(&mut input, &key(Keycode::W), true);
+        assert!(!input.jump);
+        assert_eq!(input.aim_y, 1.0);
+    }
+
+    #[test]
+    fn one_wall_cant_be_climbed_forever() {
+        load_config();
+        let mut player = Player::new(Team(0));
+        player.grounded = false;
+        player.jump = JumpState::Single;
+        let mut events = Vec::new();
+        let mut jump_off = |player: &mut Player, wall| {
+            player.wall = wall;
+            player.try_jump(&mut events)
+        };
+
+        // Going back and forth between two walls keeps giving the jump back
+        for &wall in &[1.0, -1.0, 1.0, -1.0] {
+            assert!(jump_off(&mut player, wall));
+            assert_eq!(player.jump, JumpState::Single);
+        }
+        // But the same one again uses it up
+        assert!(jump_off(&mut player, -1.0));
+        assert_eq!(player.jump, JumpState::None);
+        assert!(!jump_off(&mut player, -1.0));
+    }
+}
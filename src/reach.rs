@@ -0,0 +1,102 @@
+use std::collections::VecDeque;
+
+use crate::grid::{GridCoord, Module, Tile, GRID_HEIGHT, GRID_WIDTH};
+use crate::player;
+
+/// Can a player pass through the tile?
+fn open(module: &Module, x: GridCoord, y: GridCoord) -> bool {
+    match module[y][x] {
+        Tile::Air | Tile::Leave => true,
+        Tile::Solid(health)
+        | Tile::OneWay(health)
+        | Tile::Reinforced(health)
+        | Tile::Explosive(health)
+        | Tile::Regen(health)
+        | Tile::Surface(_, health)
+        | Tile::Hazard(_, health) => health == 0,
+        Tile::Start(_) | Tile::Bedrock => false,
+    }
+}
+
+/// Can a player pass through the tile when moving up or sideways? One way
+/// platforms only stop them coming down.
+fn open_from_below(module: &Module, x: GridCoord, y: GridCoord) -> bool {
+    match module[y][x] {
+        Tile::OneWay(_) => true,
+        _ => open(module, x, y),
+    }
+}
+
+/// Is the tile somewhere a player could stand? The bottom row stands on
+/// the grid below, which could be anything, so it never counts.
+fn standable(module: &Module, x: GridCoord, y: GridCoord) -> bool {
+    y > 0 && open(module, x, y) && !open(module, x, y - 1)
+}
+
+/// All of the tiles a player could stand on in a module, but can't get to by
+/// falling in from the grid above or jumping up from the grid below.
+///
+/// The player fits in a single tile and steers freely in the air, so the
+/// only limit is how many tiles they can climb: both jumps from the ground,
+/// or just the second one after falling off a ledge.
+pub fn unreachable_spots(module: &Module) -> Vec<(GridCoord, GridCoord)> {
+    let (single, double) = player::jump_heights();
+    let single = single as usize;
+    let double = double as usize;
+
+    // Visited states are a position and how many tiles the player can still climb
+    let mut seen = vec![[[false; GRID_WIDTH]; GRID_HEIGHT]; double + 1];
+    let mut reached = [[false; GRID_WIDTH]; GRID_HEIGHT];
+    let mut queue = VecDeque::new();
+
+    for x in 0..GRID_WIDTH {
+        if open(module, x, GRID_HEIGHT - 1) {
+            queue.push_back((x, GRID_HEIGHT - 1, single));
+        }
+        if open_from_below(module, x, 0) {
+            queue.push_back((x, 0, double.saturating_sub(1)));
+        }
+    }
+
+    while let Some((x, y, climb)) = queue.pop_front() {
+        if seen[climb][y][x] {
+            continue;
+        }
+        seen[climb][y][x] = true;
+        reached[y][x] = true;
+
+        let climb = if standable(module, x, y) {
+            double
+        } else {
+            climb
+        };
+        let mut next = Vec::with_capacity(4);
+        if x > 0 {
+            next.push((x - 1, y, climb));
+        }
+        if x + 1 < GRID_WIDTH {
+            next.push((x + 1, y, climb));
+        }
+        if y + 1 < GRID_HEIGHT && climb > 0 {
+            next.push((x, y + 1, climb - 1));
+        }
+        for (x, y, climb) in next {
+            if open_from_below(module, x, y) && !seen[climb][y][x] {
+                queue.push_back((x, y, climb));
+            }
+        }
+        if y > 0 && open(module, x, y - 1) && !seen[climb.min(single)][y - 1][x] {
+            queue.push_back((x, y - 1, climb.min(single)));
+        }
+    }
+
+    let mut spots = Vec::new();
+    for (y, row) in reached.iter().enumerate() {
+        for (x, &reached) in row.iter().enumerate() {
+            if standable(module, x, y) && !reached {
+                spots.push((x, y));
+            }
+        }
+    }
+    spots
+}
@@ -0,0 +1,386 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use ggez::event::{self, Keycode};
+
+use crate::codec::{self, Reader};
+use crate::player::{Button, ControlState};
+
+const MAGIC: &[u8; 8] = b"FALLREPL";
+const VERSION: u8 = 3;
+
+const TICK: u8 = 0;
+const JOIN: u8 = 1;
+const LEAVE: u8 = 2;
+const BUTTON: u8 = 3;
+const AXIS: u8 = 4;
+const INPUT: u8 = 5;
+
+const KEYBOARD: u8 = 0;
+const CONTROLLER: u8 = 1;
+
+/// Controller buttons and axes are stored as their index in these
+const CONTROLLER_BUTTONS: [event::Button; 15] = [
+    event::Button::A,
+    event::Button::B,
+    event::Button::X,
+    event::Button::Y,
+    event::Button::Back,
+    event::Button::Guide,
+    event::Button::Start,
+    event::Button::LeftStick,
+    event::Button::RightStick,
+    event::Button::LeftShoulder,
+    event::Button::RightShoulder,
+    event::Button::DPadUp,
+    event::Button::DPadDown,
+    event::Button::DPadLeft,
+    event::Button::DPadRight,
+];
+const CONTROLLER_AXES: [event::Axis; 6] = [
+    event::Axis::LeftX,
+    event::Axis::LeftY,
+    event::Axis::RightX,
+    event::Axis::RightY,
+    event::Axis::TriggerLeft,
+    event::Axis::TriggerRight,
+];
+
+/// How often (in ticks) the recording gets flushed to disk
+const FLUSH_INTERVAL: u32 = 60;
+
+/// A replay file is a header holding the seed and the fingerprint of the
+/// config and modules, followed by the raw input in the order it happened.
+/// Playing it back sends every button and axis through the same code as live
+/// input, with a `Tick` record ending each tick. The input of every player is
+/// recorded before each tick as well, joins and leaves only get recorded when
+/// they come over the network.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Tick,
+    Button(Button, bool),
+    Axis(i32, event::Axis, f32),
+    Input(usize, ControlState),
+    Join { slot: usize, ready: bool },
+    Leave(usize),
+}
+
+pub struct Recorder {
+    file: BufWriter<File>,
+    ticks: u32,
+    buffer: Vec<u8>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P, seed: u64, fingerprint: u64) -> io::Result<Recorder> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut header = Vec::with_capacity(25);
+        header.extend_from_slice(MAGIC);
+        codec::put_u8(&mut header, VERSION);
+        codec::put_u64(&mut header, seed);
+        codec::put_u64(&mut header, fingerprint);
+        file.write_all(&header)?;
+        Ok(Recorder {
+            file,
+            ticks: 0,
+            buffer: Vec::with_capacity(64),
+        })
+    }
+
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        self.buffer.clear();
+        match record {
+            Record::Tick => {
+                codec::put_u8(&mut self.buffer, TICK);
+                self.ticks += 1;
+            }
+            Record::Button(btn, pressed) => {
+                codec::put_u8(&mut self.buffer, BUTTON);
+                put_button(&mut self.buffer, btn);
+                codec::put_u8(&mut self.buffer, *pressed as u8);
+            }
+            Record::Axis(id, axis, value) => {
+                let index = CONTROLLER_AXES.iter().position(|x| x == axis).unwrap();
+                codec::put_u8(&mut self.buffer, AXIS);
+                codec::put_u32(&mut self.buffer, *id as u32);
+                codec::put_u8(&mut self.buffer, index as u8);
+                codec::put_f32(&mut self.buffer, *value);
+            }
+            Record::Input(slot, state) => {
+                codec::put_u8(&mut self.buffer, INPUT);
+                codec::put_u8(&mut self.buffer, *slot as u8);
+                codec::put_control_state(&mut self.buffer, state);
+            }
+            Record::Join { slot, ready } => {
+                codec::put_u8(&mut self.buffer, JOIN);
+                codec::put_u8(&mut self.buffer, *slot as u8);
+                codec::put_u8(&mut self.buffer, *ready as u8);
+            }
+            Record::Leave(slot) => {
+                codec::put_u8(&mut self.buffer, LEAVE);
+                codec::put_u8(&mut self.buffer, *slot as u8);
+            }
+        }
+        self.file.write_all(&self.buffer)?;
+        if let Record::Tick = record {
+            if self.ticks.is_multiple_of(FLUSH_INTERVAL) {
+                self.file.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn put_button(out: &mut Vec<u8>, btn: &Button) {
+    match btn {
+        Button::Keyboard(key) => {
+            codec::put_u8(out, KEYBOARD);
+            codec::put_u32(out, *key as i32 as u32);
+        }
+        Button::Controller(id, button) => {
+            let index = CONTROLLER_BUTTONS.iter().position(|x| x == button).unwrap();
+            codec::put_u8(out, CONTROLLER);
+            codec::put_u32(out, *id as u32);
+            codec::put_u8(out, index as u8);
+        }
+    }
+}
+
+fn read_button(reader: &mut Reader) -> io::Result<Button> {
+    match reader.u8()? {
+        KEYBOARD => {
+            let code = reader.u32()? as i32;
+            Keycode::from_i32(code)
+                .map(Button::Keyboard)
+                .ok_or_else(|| codec::invalid_data(format!("Unknown key {}", code)))
+        }
+        CONTROLLER => {
+            let id = reader.u32()? as i32;
+            let index = reader.u8()? as usize;
+            CONTROLLER_BUTTONS
+                .get(index)
+                .map(|button| Button::Controller(id, *button))
+                .ok_or_else(|| codec::invalid_data(format!("Unknown button {}", index)))
+        }
+        kind => Err(codec::invalid_data(format!("Unknown button kind {}", kind))),
+    }
+}
+
+pub struct Replay {
+    pub seed: u64,
+    /// The fingerprint of the config and modules the replay was recorded with
+    pub fingerprint: u64,
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl Replay {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Replay> {
+        let bytes = fs::read(path)?;
+        let mut reader = Reader::new(&bytes);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(codec::invalid_data("Not a replay file".into()));
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(codec::invalid_data(format!(
+                "Unsupported replay version {} (expected {})",
+                version, VERSION
+            )));
+        }
+        let seed = reader.u64()?;
+        let fingerprint = reader.u64()?;
+        Ok(Replay {
+            seed,
+            fingerprint,
+            bytes,
+            pos: MAGIC.len() + 17,
+        })
+    }
+
+    /// Read the next record, or `None` once the whole replay has been played
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut reader = Reader::new(&self.bytes[self.pos..]);
+        if reader.is_empty() {
+            return Ok(None);
+        }
+        let record = match reader.u8()? {
+            TICK => Record::Tick,
+            BUTTON => Record::Button(read_button(&mut reader)?, reader.u8()? != 0),
+            AXIS => {
+                let id = reader.u32()? as i32;
+                let index = reader.u8()? as usize;
+                let axis = CONTROLLER_AXES
+                    .get(index)
+                    .ok_or_else(|| codec::invalid_data(format!("Unknown axis {}", index)))?;
+                Record::Axis(id, *axis, reader.f32()?)
+            }
+            INPUT => Record::Input(reader.slot()?, reader.control_state()?),
+            JOIN => Record::Join {
+                slot: reader.slot()?,
+                ready: reader.u8()? != 0,
+            },
+            LEAVE => Record::Leave(reader.slot()?),
+            tag => {
+                return Err(codec::invalid_data(format!(
+                    "Unknown replay record {} at byte {}",
+                    tag, self.pos
+                )));
+            }
+        };
+        self.pos = self.bytes.len() - reader.remaining();
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::player::{Axis, Controls};
+    use crate::simulation::{tests::test_match, Inputs};
+
+    const SEED: u64 = 11;
+    const FINGERPRINT: u64 = 0x0123_4567_89ab_cdef;
+
+    fn test_controls() -> [Controls; 2] {
+        let key = Button::Keyboard;
+        let pad = |button| Button::Controller(0, button);
+        [
+            Controls {
+                lr: Axis::Buttons(key(Keycode::A), key(Keycode::D)),
+                aim: Axis::Buttons(key(Keycode::S), key(Keycode::W)),
+                jump: key(Keycode::W),
+                shoot: key(Keycode::Tab),
+                repair: key(Keycode::Q),
+                dash: key(Keycode::LShift),
+            },
+            Controls {
+                lr: Axis::Analog(0, event::Axis::LeftX),
+                aim: Axis::Analog(0, event::Axis::RightY),
+                jump: pad(event::Button::A),
+                shoot: pad(event::Button::B),
+                repair: pad(event::Button::X),
+                dash: pad(event::Button::Y),
+            },
+        ]
+    }
+
+    /// Mash the keyboard and the controller, toggling one button at a time
+    fn scripted_records(frame: u32, held: &mut Vec<Button>) -> Vec<Record> {
+        let keys = [
+            Keycode::A,
+            Keycode::D,
+            Keycode::W,
+            Keycode::S,
+            Keycode::Tab,
+            Keycode::LShift,
+            Keycode::Q,
+        ];
+        let buttons = [
+            event::Button::A,
+            event::Button::B,
+            event::Button::X,
+            event::Button::Y,
+        ];
+        let mut records = Vec::new();
+        let btn = match frame % 4 {
+            0 => Some(Button::Keyboard(
+                keys[(frame / 4 * 5) as usize % keys.len()],
+            )),
+            2 => Some(Button::Controller(
+                0,
+                buttons[(frame / 4 * 3) as usize % buttons.len()],
+            )),
+            _ => None,
+        };
+        if let Some(btn) = btn {
+            let pressed = !held.contains(&btn);
+            if pressed {
+                held.push(btn);
+            } else {
+                held.retain(|x| *x != btn);
+            }
+            records.push(Record::Button(btn, pressed));
+        }
+        if frame % 10 == 5 {
+            let value = ((frame / 10) % 5) as f32 / 2.0 - 1.0;
+            records.push(Record::Axis(0, event::Axis::LeftX, value));
+            records.push(Record::Axis(0, event::Axis::RightX, -value));
+            records.push(Record::Axis(0, event::Axis::RightY, value * 0.5));
+        }
+        records
+    }
+
+    /// What `MainState` does with a record, minus joining and the window
+    fn apply(controls: &[Controls], inputs: &mut Inputs, record: &Record) {
+        for (controls, input) in controls.iter().zip(inputs.iter_mut()) {
+            match record {
+                Record::Button(btn, pressed) => {
+                    controls.button(input, btn, *pressed);
+                }
+                Record::Axis(id, axis, value) => {
+                    controls.axis(input, &Axis::Analog(*id, *axis), *value)
+                }
+                _ => (),
+            }
+        }
+        if let Record::Input(slot, state) = record {
+            let input = &mut inputs[*slot];
+            *input = ControlState {
+                aim_stick: input.aim_stick,
+                move_stick: input.move_stick,
+                ..*state
+            };
+        }
+    }
+
+    #[test]
+    fn replays_match_the_recorded_game() {
+        let path = std::env::temp_dir().join(format!("fall-replay-{}.bin", process::id()));
+        let controls = test_controls();
+
+        let mut sim = test_match(SEED);
+        let mut inputs = Inputs::default();
+        let mut recorder = Recorder::create(&path, SEED, FINGERPRINT).unwrap();
+        let mut held = Vec::new();
+        let mut expected = Vec::new();
+        for frame in 0..600 {
+            for record in scripted_records(frame, &mut held) {
+                recorder.record(&record).unwrap();
+                apply(&controls, &mut inputs, &record);
+            }
+            for (slot, input) in inputs.iter().enumerate() {
+                if sim.players[slot].is_some() {
+                    recorder.record(&Record::Input(slot, *input)).unwrap();
+                }
+            }
+            recorder.record(&Record::Tick).unwrap();
+            let events = sim.step(&inputs).to_vec();
+            expected.push((events, sim.checksum()));
+        }
+        assert!(!sim.in_menu);
+        drop(recorder);
+
+        let mut replay = Replay::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(replay.fingerprint, FINGERPRINT);
+        let mut sim = test_match(replay.seed);
+        let mut inputs = Inputs::default();
+        let mut frame = 0;
+        while let Some(record) = replay.next_record().unwrap() {
+            if record != Record::Tick {
+                apply(&controls, &mut inputs, &record);
+                continue;
+            }
+            let events = sim.step(&inputs).to_vec();
+            assert_eq!((events, sim.checksum()), expected[frame], "frame {}", frame);
+            frame += 1;
+        }
+        assert_eq!(frame, expected.len());
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, Rng};
+
+use crate::codec::{self, Reader};
+use crate::player::{ControlState, Player, Team};
+use crate::simulation::{Event, Inputs, Simulation};
+
+const MAGIC: &[u8; 4] = b"FLRB";
+const VERSION: u8 = 2;
+
+/// How many frames we are allowed to run ahead of the last confirmed remote input
+const MAX_PREDICTION: u32 = 8;
+/// How many of our own inputs (at most) are repeated in every packet
+const MAX_REDUNDANT_INPUTS: usize = 32;
+/// How many recent checksums are sent along with every packet
+const CHECKSUMS_PER_PACKET: usize = 4;
+const MAX_PACKET: usize = 2048;
+
+pub struct Options {
+    pub local_port: u16,
+    pub remote: String,
+    pub slot: usize,
+    /// Frames between reading a local input and simulating with it
+    pub input_delay: u32,
+    /// Extra one way latency added to every outgoing packet, for testing
+    pub sim_latency: Duration,
+    /// Chance of dropping every outgoing packet, for testing
+    pub sim_loss: f64,
+}
+
+/// A two player peer to peer session. Remote inputs that haven't arrived yet
+/// are predicted, and when they turn out to be wrong the simulation is rolled
+/// back to a saved snapshot and simulated again with the real inputs.
+pub struct Session {
+    socket: UdpSocket,
+    remote: SocketAddr,
+    local_slot: usize,
+    remote_slot: usize,
+    seed: u64,
+    input_delay: u32,
+    sim_latency: Duration,
+    sim_loss: f64,
+    outgoing: VecDeque<(Instant, Vec<u8>)>,
+
+    started: bool,
+    /// The next frame to simulate
+    frame: u32,
+    local_inputs: BTreeMap<u32, ControlState>,
+    remote_inputs: BTreeMap<u32, ControlState>,
+    /// Every remote input before this frame has arrived
+    remote_confirmed: u32,
+    /// How far the remote has confirmed our inputs
+    remote_ack: u32,
+    /// The remote inputs that were guessed while simulating each unconfirmed frame
+    predictions: BTreeMap<u32, ControlState>,
+    rollback_to: Option<u32>,
+    /// The state before simulating each frame that might still be rolled back
+    snapshots: VecDeque<(u32, Simulation)>,
+    local_checksums: BTreeMap<u32, u64>,
+    remote_checksums: BTreeMap<u32, u64>,
+    desynced: bool,
+}
+
+impl Session {
+    pub fn new(options: &Options, seed: u64) -> io::Result<Session> {
+        if options.slot > 1 {
+            return Err(codec::invalid_data("The peer slot must be 0 or 1".into()));
+        }
+        let remote = options.remote.to_socket_addrs()?.next().ok_or_else(|| {
+            codec::invalid_data(format!("Could not resolve '{}'", options.remote))
+        })?;
+        let socket = UdpSocket::bind(("0.0.0.0", options.local_port))?;
+        socket.set_nonblocking(true)?;
+        println!(
+            "Waiting for {} on port {}",
+            remote,
+            socket.local_addr()?.port()
+        );
+
+        let mut local_inputs = BTreeMap::new();
+        for frame in 0..options.input_delay {
+            local_inputs.insert(frame, ControlState::default());
+        }
+
+        Ok(Session {
+            socket,
+            remote,
+            local_slot: options.slot,
+            remote_slot: 1 - options.slot,
+            seed,
+            input_delay: options.input_delay,
+            sim_latency: options.sim_latency,
+            sim_loss: options.sim_loss,
+            outgoing: VecDeque::new(),
+            started: false,
+            frame: 0,
+            local_inputs,
+            remote_inputs: BTreeMap::new(),
+            remote_confirmed: 0,
+            remote_ack: 0,
+            predictions: BTreeMap::new(),
+            rollback_to: None,
+            snapshots: VecDeque::new(),
+            local_checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            desynced: false,
+        })
+    }
+
+    pub fn local_slot(&self) -> usize {
+        self.local_slot
+    }
+
+    /// Advance the session by one tick, pushing the events of any newly simulated
+    /// frame. Frames that get simulated again after a rollback don't repeat their events.
+    pub fn tick(&mut self, sim: &mut Simulation, local: ControlState, events: &mut Vec<Event>) {
+        self.poll(sim);
+
+        if let Some(frame) = self.rollback_to.take() {
+            self.rollback(sim, frame);
+        }
+        // Don't get too far ahead of the remote, or rolling back gets expensive
+        if self.started && self.frame < self.remote_confirmed + MAX_PREDICTION {
+            self.local_inputs
+                .insert(self.frame + self.input_delay, local);
+            self.simulate(sim, Some(events));
+        }
+        self.check_desync();
+
+        self.send();
+        self.flush();
+    }
+
+    fn start(&mut self, sim: &mut Simulation, seed: u64) {
+        // Slot 0 decides the seed
+        if self.local_slot == 1 {
+            self.seed = seed;
+        }
+        println!("Connected to {}, seed: {}", self.remote, self.seed);
+        *sim = Simulation::new(sim.modules.to_vec(), self.seed);
+        sim.players[0] = Some(Player::new(Team(0)));
+        sim.players[1] = Some(Player::new(Team(1)));
+        self.started = true;
+    }
+
+    fn rollback(&mut self, sim: &mut Simulation, frame: u32) {
+        let snapshot = self.snapshots.iter().find(|x| x.0 == frame);
+        let snapshot = match snapshot {
+            Some((_, snapshot)) => snapshot.clone(),
+            None => {
+                println!("Missing the snapshot for frame {}, can't roll back", frame);
+                return;
+            }
+        };
+        *sim = snapshot;
+        let current = self.frame;
+        self.frame = frame;
+        while self.frame < current {
+            self.simulate(sim, None);
+        }
+    }
+
+    fn simulate(&mut self, sim: &mut Simulation, events: Option<&mut Vec<Event>>) {
+        let frame = self.frame;
+        self.snapshots.retain(|x| x.0 < frame);
+        self.snapshots.push_back((frame, sim.clone()));
+        // Every input before a confirmed snapshot is known, so it can't change any
+        // more and both peers should agree on it
+        while self
+            .snapshots
+            .front()
+            .is_some_and(|x| x.0 < self.remote_confirmed)
+        {
+            let (confirmed, snapshot) = self.snapshots.pop_front().unwrap();
+            self.local_checksums.insert(confirmed, snapshot.checksum());
+        }
+
+        let mut inputs = Inputs::default();
+        inputs[self.local_slot] = self.local_inputs[&frame];
+        inputs[self.remote_slot] = match self.remote_inputs.get(&frame) {
+            Some(input) => *input,
+            None => {
+                let prediction = self.predict();
+                self.predictions.insert(frame, prediction);
+                prediction
+            }
+        };
+
+        let frame_events = sim.step(&inputs);
+        if let Some(events) = events {
+            events.extend_from_slice(frame_events);
+        }
+        self.frame += 1;
+    }
+
+    /// Guess that the remote player is still holding the last thing we heard of
+    fn predict(&self) -> ControlState {
+        self.remote_inputs
+            .range(..self.remote_confirmed)
+            .next_back()
+            .map(|x| *x.1)
+            .unwrap_or_default()
+    }
+
+    fn check_desync(&mut self) {
+        let mut checked = Vec::new();
+        for (frame, remote) in &self.remote_checksums {
+            if let Some(local) = self.local_checksums.get(frame) {
+                if local != remote && !self.desynced {
+                    println!(
+                        "Desync detected at frame {}: local {:016x}, remote {:016x}",
+                        frame, local, remote
+                    );
+                    self.desynced = true;
+                }
+                checked.push(*frame);
+            }
+        }
+        for frame in checked {
+            self.remote_checksums.remove(&frame);
+        }
+        // Keep enough of our own checksums around to repeat them in packets
+        while self.local_checksums.len() > 256 {
+            let first = *self.local_checksums.keys().next().unwrap();
+            self.local_checksums.remove(&first);
+        }
+    }
+
+    fn poll(&mut self, sim: &mut Simulation) {
+        let mut packet = [0; MAX_PACKET];
+        loop {
+            let len = match self.socket.recv_from(&mut packet) {
+                Ok((len, addr)) if addr == self.remote => len,
+                Ok(_) => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    println!("Network error: {}", err);
+                    break;
+                }
+            };
+            if let Err(err) = self.handle(&packet[..len], sim) {
+                println!("Bad packet from {}: {}", self.remote, err);
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &[u8], sim: &mut Simulation) -> io::Result<()> {
+        let mut reader = Reader::new(packet);
+        if reader.bytes(MAGIC.len())? != MAGIC || reader.u8()? != VERSION {
+            return Err(codec::invalid_data("Not a rollback packet".into()));
+        }
+        let slot = reader.slot()?;
+        let seed = reader.u64()?;
+        let input_delay = reader.u32()?;
+        if slot != self.remote_slot {
+            return Err(codec::invalid_data(format!(
+                "Both peers are using slot {}",
+                slot
+            )));
+        }
+        if !self.started {
+            if input_delay != self.input_delay {
+                println!(
+                    "Warning: the remote uses an input delay of {} instead of {}",
+                    input_delay, self.input_delay
+                );
+            }
+            self.start(sim, seed);
+        }
+
+        self.remote_ack = self.remote_ack.max(reader.u32()?);
+        let first = reader.u32()?;
+        let count = reader.u8()?;
+        let last = first
+            .checked_add(count as u32)
+            .ok_or_else(|| codec::invalid_data("Input frame numbers overflow".into()))?;
+        for frame in first..last {
+            let input = reader.control_state()?;
+            if frame < self.remote_confirmed || self.remote_inputs.contains_key(&frame) {
+                continue;
+            }
+            self.remote_inputs.insert(frame, input);
+            if let Some(prediction) = self.predictions.remove(&frame) {
+                if prediction != input {
+                    let target = self.rollback_to.unwrap_or(frame).min(frame);
+                    self.rollback_to = Some(target);
+                }
+            }
+        }
+        while self.remote_inputs.contains_key(&self.remote_confirmed) {
+            self.remote_confirmed += 1;
+        }
+        // Forget inputs that can't be needed for a rollback any more. The one
+        // before the confirmed frame is kept for predictions, a pending
+        // rollback still has to simulate everything since its frame again, and
+        // a remote that's ahead of us has sent inputs we haven't simulated yet.
+        let keep = self
+            .rollback_to
+            .unwrap_or(self.remote_confirmed)
+            .min(self.remote_confirmed)
+            .min(self.frame)
+            .saturating_sub(1);
+        self.remote_inputs = self.remote_inputs.split_off(&keep);
+        self.predictions = self.predictions.split_off(&self.remote_confirmed);
+
+        let checksums = reader.u8()?;
+        for _ in 0..checksums {
+            let frame = reader.u32()?;
+            let checksum = reader.u64()?;
+            self.remote_checksums.insert(frame, checksum);
+        }
+        Ok(())
+    }
+
+    fn send(&mut self) {
+        let mut packet = Vec::with_capacity(MAX_PACKET);
+        packet.extend_from_slice(MAGIC);
+        codec::put_u8(&mut packet, VERSION);
+        codec::put_u8(&mut packet, self.local_slot as u8);
+        codec::put_u64(&mut packet, self.seed);
+        codec::put_u32(&mut packet, self.input_delay);
+        codec::put_u32(&mut packet, self.remote_confirmed);
+
+        // Local inputs are still needed to roll back to any unconfirmed frame
+        let keep = self.remote_ack.min(self.remote_confirmed).min(self.frame);
+        self.local_inputs = self.local_inputs.split_off(&keep);
+
+        // Repeat every input the remote hasn't confirmed yet, so lost packets don't matter
+        let unacked: Vec<_> = self
+            .local_inputs
+            .range(self.remote_ack..)
+            .take(MAX_REDUNDANT_INPUTS)
+            .collect();
+        codec::put_u32(&mut packet, unacked.first().map_or(0, |x| *x.0));
+        codec::put_u8(&mut packet, unacked.len() as u8);
+        for (_, input) in unacked {
+            codec::put_control_state(&mut packet, input);
+        }
+
+        let checksums: Vec<_> = self
+            .local_checksums
+            .iter()
+            .rev()
+            .take(CHECKSUMS_PER_PACKET)
+            .collect();
+        codec::put_u8(&mut packet, checksums.len() as u8);
+        for (frame, checksum) in checksums {
+            codec::put_u32(&mut packet, *frame);
+            codec::put_u64(&mut packet, *checksum);
+        }
+
+        if self.sim_loss > 0.0 && thread_rng().gen_bool(self.sim_loss) {
+            return;
+        }
+        self.outgoing
+            .push_back((Instant::now() + self.sim_latency, packet));
+    }
+
+    /// Send the packets whose simulated latency has passed
+    fn flush(&mut self) {
+        let now = Instant::now();
+        while self.outgoing.front().is_some_and(|x| x.0 <= now) {
+            let (_, packet) = self.outgoing.pop_front().unwrap();
+            if let Err(err) = self.socket.send_to(&packet, self.remote) {
+                println!("Could not send to {}: {}", self.remote, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::simulation::tests::{scripted_inputs, test_simulation};
+
+    fn options(slot: usize, remote: String) -> Options {
+        Options {
+            local_port: 0,
+            remote,
+            slot,
+            input_delay: 1,
+            sim_latency: Duration::from_millis(20),
+            sim_loss: 0.25,
+        }
+    }
+
+    /// Walks differently every frame, so most predictions are wrong
+    fn changing_input(slot: usize, frame: u32) -> ControlState {
+        let mut input = scripted_inputs(frame)[slot];
+        input.lr = ((frame * 7 + slot as u32 * 3) % 5) as f32 / 2.0 - 1.0;
+        input
+    }
+
+    /// Play a match between two local peers, where the second one only gets to
+    /// tick once every `b_every` ticks of the first
+    fn play(b_every: u32) {
+        const FRAMES: u32 = 300;
+        let mut a = Session::new(&options(0, "127.0.0.1:9".into()), 5).unwrap();
+        let port = a.socket.local_addr().unwrap().port();
+        let mut b = Session::new(&options(1, format!("127.0.0.1:{}", port)), 6).unwrap();
+        a.remote = format!("127.0.0.1:{}", b.socket.local_addr().unwrap().port())
+            .parse()
+            .unwrap();
+
+        let mut sim_a = test_simulation(1);
+        let mut sim_b = test_simulation(2);
+        let mut events = Vec::new();
+        let start = Instant::now();
+        let mut ticks = 0;
+        while a.frame < FRAMES || b.frame < FRAMES {
+            assert!(start.elapsed() < Duration::from_secs(30), "Timed out");
+            let local = changing_input(0, a.frame);
+            a.tick(&mut sim_a, local, &mut events);
+            if ticks % b_every == 0 {
+                let local = changing_input(1, b.frame);
+                b.tick(&mut sim_b, local, &mut events);
+            }
+            ticks += 1;
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(!a.desynced && !b.desynced);
+        let mut compared = 0;
+        for (frame, checksum) in &a.local_checksums {
+            if let Some(other) = b.local_checksums.get(frame) {
+                assert_eq!(checksum, other, "frame {}", frame);
+                compared += 1;
+            }
+        }
+        assert!(compared > 100, "Only {} frames were confirmed", compared);
+    }
+
+    #[test]
+    fn peers_agree_despite_lost_and_late_packets() {
+        play(1);
+    }
+
+    #[test]
+    fn peers_agree_when_one_falls_behind() {
+        play(3);
+    }
+}
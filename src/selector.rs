@@ -0,0 +1,116 @@
+use rand::Rng;
+
+use crate::config::MODULES;
+use crate::grid::ModuleDef;
+
+/// Picks the module for every new grid. A match starts out with the lowest
+/// tier and moves up a tier every few grids, or every so many seconds,
+/// whichever comes first. Modules from lower tiers still show up, but less
+/// and less often.
+#[derive(Debug, Default, Clone, Hash)]
+pub struct ModuleSelector {
+    grids_fallen: u32,
+    ticks: u32,
+    last: Option<usize>,
+}
+
+impl ModuleSelector {
+    /// Called once for every tick of the match
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    pub fn grid_fell(&mut self) {
+        self.grids_fallen += 1;
+    }
+
+    /// The difficulty tier the match has reached so far
+    pub fn tier(&self) -> u32 {
+        let by_grids = self.grids_fallen / MODULES.grids_per_tier.max(1);
+        let seconds = self.ticks as f32 * crate::DT;
+        let by_time = if MODULES.seconds_per_tier > 0.0 {
+            (seconds / MODULES.seconds_per_tier) as u32
+        } else {
+            0
+        };
+        by_grids.max(by_time)
+    }
+
+    /// Pick the index of the next module, never the same one twice in a row
+    /// unless there is nothing else to pick. The lobby is never picked unless
+    /// it's the only module there is.
+    pub fn choose(&mut self, modules: &[ModuleDef], mode: &str, rng: &mut impl Rng) -> usize {
+        let mut candidates: Vec<usize> = (0..modules.len())
+            .filter(|&i| {
+                let module = &modules[i];
+                module.allows_mode(mode) && module.weight > 0.0 && !module.has_tag("lobby")
+            })
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..modules.len()).collect();
+        }
+
+        // Don't go looking for a tier nobody has made modules for, either
+        // above the top tier or below the bottom one
+        let tiers = candidates.iter().map(|&i| modules[i].tier);
+        let bottom_tier = tiers.clone().min().unwrap_or(0);
+        let top_tier = tiers.max().unwrap_or(0);
+        let tier = self.tier().min(top_tier).max(bottom_tier);
+        candidates.retain(|&i| modules[i].tier <= tier);
+        if candidates.len() > 1 {
+            candidates.retain(|&i| Some(i) != self.last);
+        }
+
+        let weight = |i: usize| {
+            let module = &modules[i];
+            module.weight * MODULES.tier_falloff.powi((tier - module.tier) as i32)
+        };
+        let total: f32 = candidates.iter().map(|&i| weight(i)).sum();
+        let choice = if total > 0.0 {
+            let mut roll = rng.gen::<f32>() * total;
+            let last = *candidates.last().unwrap();
+            candidates
+                .into_iter()
+                .find(|&i| {
+                    roll -= weight(i);
+                    roll < 0.0
+                })
+                .unwrap_or(last)
+        } else {
+            *rng.choose(&candidates).unwrap()
+        };
+
+        self.last = Some(choice);
+        choice
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{Tile, GRID_HEIGHT, GRID_WIDTH};
+    use crate::simulation::tests::load_config;
+
+    fn module(name: &str, tier: u32, tags: &[&str]) -> ModuleDef {
+        let mut module = ModuleDef::new(name.into(), [[Tile::Air; GRID_WIDTH]; GRID_HEIGHT]);
+        module.tier = tier;
+        module.tags = tags.iter().map(|&x| x.into()).collect();
+        module
+    }
+
+    #[test]
+    fn matches_start_at_the_lowest_tier_there_is() {
+        load_config();
+        let modules = vec![
+            module("lobby", 0, &["lobby"]),
+            module("a", 2, &[]),
+            module("b", 2, &[]),
+        ];
+        let mut selector = ModuleSelector::default();
+        let mut rng = crate::simulation::new_rng(3);
+        for _ in 0..20 {
+            let choice = selector.choose(&modules, "classic", &mut rng);
+            assert!(choice == 1 || choice == 2, "picked the lobby");
+        }
+    }
+}
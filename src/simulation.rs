@@ -0,0 +1,715 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+use ggez::graphics::{Rect, Vector2};
+use rand::{prng::XorShiftRng, Rng, RngCore, SeedableRng};
+
+use crate::bullet::{self, Bullet};
+use crate::config::{GAME, GENERATOR, GRID, PLAYER};
+use crate::draw;
+use crate::explosion;
+use crate::generator;
+use crate::grid::{self, Grid, GridState, ModuleDef};
+use crate::math;
+use crate::pickup;
+use crate::player::{ControlState, Player, Team};
+use crate::selector::ModuleSelector;
+use crate::sound::SoundEffect;
+use crate::weapon;
+
+/// The generator behind all gameplay randomness, so that a seed and a list of
+/// inputs always reproduce the same match
+pub type GameRng = XorShiftRng;
+
+/// The control state of every player slot for a single tick
+pub type Inputs = [ControlState; 4];
+
+/// Things that happened during a tick that a front end might want to react to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Sound(SoundEffect),
+    GridLanded { height: f32 },
+    GridFellOffscreen,
+    PlayerDied(Team),
+    PlayerLeft(Team),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MenuPhase {
+    InMenu,
+    DropMenu,
+    InGame,
+}
+
+/// All of the game state, advanced one `DT` tick at a time without needing
+/// a window or an audio device.
+#[derive(Clone)]
+pub struct Simulation {
+    pub in_menu: bool,
+    pub menu_phase: MenuPhase,
+    // Grids are stored from lowest visually to highest
+    pub grids: Vec<Grid>,
+    /// Shared between clones, so rollback snapshots don't copy every module
+    pub modules: Rc<[ModuleDef]>,
+    pub selector: ModuleSelector,
+    pub players: [Option<Player>; 4],
+    pub bullets: Vec<Bullet>,
+    pub rng: GameRng,
+    events: Vec<Event>,
+}
+
+pub fn new_rng(seed: u64) -> GameRng {
+    let mut bytes = [0; 16];
+    for i in 0..8 {
+        bytes[i] = (seed >> (i * 8)) as u8;
+        bytes[i + 8] = (!seed >> (i * 8)) as u8;
+    }
+    GameRng::from_seed(bytes)
+}
+
+pub fn somes_mut<'a, T: 'a>(
+    i: impl IntoIterator<Item = &'a mut Option<T>>,
+) -> impl Iterator<Item = &'a mut T> {
+    i.into_iter().filter_map(|x| x.as_mut())
+}
+
+/// Players holding repair fix up the grid they're standing on, which shares
+/// a cooldown with shooting
+pub fn repair_grids(players: &mut [Option<Player>], grids: &mut [Grid], events: &mut Vec<Event>) {
+    let bodies: Vec<Rect> = players
+        .iter()
+        .flatten()
+        .filter(|player| player.alive)
+        .map(|player| math::rect_from_point(player.pos(), PLAYER.width, PLAYER.height))
+        .collect();
+    for player in somes_mut(players) {
+        if !player.alive || !player.grounded || !player.control_state.repair {
+            continue;
+        }
+        if player.cooldown > 0.0 {
+            continue;
+        }
+        if let Some(i) = grid_below(player, grids) {
+            if grids[i].repair(GRID.repair_amount, &bodies) > 0 {
+                events.push(Event::Sound(SoundEffect::Repair));
+            }
+            player.cooldown = GRID.repair_cooldown;
+        }
+    }
+}
+
+/// The grid right under a player's feet, if there is one
+fn grid_below(player: &Player, grids: &[Grid]) -> Option<usize> {
+    let below = player.center() - Vector2::new(0.0, PLAYER.height / 2.0 + 0.1);
+    grids.iter().position(|grid| grid.tile_at(below).is_some())
+}
+
+impl Simulation {
+    pub fn new(mut modules: Vec<ModuleDef>, seed: u64) -> Simulation {
+        // Generated modules depend on the seed, so replace any from an earlier match
+        modules.retain(|x| !x.has_tag("generated"));
+        if GENERATOR.enabled {
+            modules.extend(generator::generate_pool(seed));
+        }
+        let lobby = modules[grid::lobby_index(&modules)].tiles;
+        let grids = vec![
+            Grid::new_from_module(0.0, lobby),
+            Grid::new_from_module(grid::GRID_HEIGHT as f32, lobby),
+            Grid::new_from_module((grid::GRID_HEIGHT * 2) as f32, lobby),
+        ];
+
+        Simulation {
+            in_menu: true,
+            menu_phase: MenuPhase::InMenu,
+            grids,
+            modules: modules.into(),
+            selector: ModuleSelector::default(),
+            players: [None, None, None, None],
+            bullets: Vec::with_capacity(20),
+            rng: new_rng(seed),
+            events: Vec::new(),
+        }
+    }
+
+    /// Advance the game by one `DT` tick, returning everything that happened
+    pub fn step(&mut self, inputs: &Inputs) -> &[Event] {
+        self.events.clear();
+
+        for (player, input) in self.players.iter_mut().zip(inputs) {
+            if let Some(player) = player {
+                // In the lobby the repair button picks the next weapon instead
+                if self.in_menu && input.repair && !player.control_state.repair {
+                    player.weapon = weapon::next(player.weapon);
+                }
+                player.control_state = *input;
+            }
+        }
+
+        self.update_menu();
+        if self.menu_phase == MenuPhase::InGame {
+            self.selector.tick();
+        }
+
+        for i in 0..self.grids.len() {
+            if i == 0 {
+                let target = if self.grids[0].state == GridState::Dead {
+                    -(grid::GRID_HEIGHT as f32)
+                } else {
+                    0.0
+                };
+                self.grids[i].fixed_update(&mut self.events, target);
+            } else {
+                let offset = self.grids[i - 1].world_offset.y + grid::GRID_HEIGHT as f32;
+                self.grids[i].fixed_update(&mut self.events, offset);
+            }
+        }
+
+        for player in somes_mut(&mut self.players) {
+            player.update(&mut self.bullets, &mut self.events);
+        }
+
+        for player in somes_mut(&mut self.players) {
+            player.fixed_update(&self.grids, &mut self.events);
+
+            // If the player is dead attempt to respawn them
+            if !player.alive && self.menu_phase != MenuPhase::DropMenu {
+                let mut indicies: Vec<_> = (0..self.grids.len()).collect();
+                self.rng.shuffle(&mut indicies);
+                for i in indicies {
+                    // Avoid spawning on the lowest grid if too damanged
+                    // (might be instant death!)
+                    if i == 0 && self.grids[0].percent_tiles_alive() < GRID.no_spawn_threshold {
+                        continue;
+                    }
+                    // Don't spawn above the screen.
+                    if self.grids[i].world_offset.y > draw::WORLD_HEIGHT {
+                        continue;
+                    }
+                    if player.respawn(&self.grids[i], &mut self.rng) {
+                        break;
+                    }
+                }
+
+                if !player.alive {
+                    println!("Player {:?} cant find a spot", player.team);
+                }
+            }
+        }
+
+        if !self.in_menu {
+            repair_grids(&mut self.players, &mut self.grids, &mut self.events);
+        }
+        if self.menu_phase == MenuPhase::InGame {
+            pickup::spawn(&mut self.grids, &mut self.rng);
+        }
+        pickup::collect(&mut self.grids, &mut self.players, &mut self.events);
+
+        for bullet in &mut self.bullets {
+            bullet.fixed_update(
+                &mut self.events,
+                &mut self.grids,
+                &mut self.players,
+                self.in_menu,
+            );
+        }
+        bullet::collide_bullets(&mut self.bullets);
+        self.bullets.retain(|bullet| bullet.is_alive);
+
+        if self.rng.gen_bool(0.2) {
+            let grid_id = *self
+                .rng
+                .choose(&[
+                    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2,
+                ])
+                .unwrap();
+            let x = self.rng.gen_range(0, grid::GRID_WIDTH);
+            let y = self.rng.gen_range(0, grid::GRID_HEIGHT);
+            self.grids[grid_id].damage_tile(x, y);
+        }
+
+        explosion::resolve(&mut self.grids, &mut self.players, &mut self.events);
+
+        if self.grids.len() > 0 && self.grids[0].world_offset.y <= -(grid::GRID_HEIGHT as f32) {
+            self.grids.remove(0);
+            if self.menu_phase == MenuPhase::InGame {
+                self.selector.grid_fell();
+            }
+            let next = self
+                .selector
+                .choose(&self.modules, &GAME.mode, &mut self.rng);
+            self.grids.push(Grid::new_from_module(
+                grid::GRID_HEIGHT as f32 * 3.0,
+                self.modules[next].tiles,
+            ));
+            self.events.push(Event::GridFellOffscreen);
+            self.events
+                .push(Event::Sound(SoundEffect::GridFallOffscreen));
+        }
+
+        // Players standing still only get pushed out of the ground every
+        // other tick, so having been on it the tick before counts too
+        let mut repairable = vec![false; self.grids.len()];
+        for player in self.players.iter().flatten() {
+            let standing = player.grounded || player.frames_since_grounded == 0;
+            if self.in_menu || !player.alive || !standing {
+                continue;
+            }
+            if let Some(i) = grid_below(player, &self.grids) {
+                repairable[i] = true;
+            }
+        }
+        for (grid, &repairable) in self.grids.iter_mut().zip(&repairable) {
+            grid.update(repairable);
+        }
+
+        &self.events
+    }
+
+    /// A hash of the game state, peers running the same match should always agree on it
+    pub fn checksum(&self) -> u64 {
+        let Simulation {
+            in_menu,
+            menu_phase,
+            grids,
+            // Modules never change during a match
+            modules: _,
+            selector,
+            players,
+            bullets,
+            rng,
+            // Only describes what happened during the last step
+            events: _,
+        } = self;
+        let mut hasher = DefaultHasher::new();
+        (in_menu, *menu_phase as u8, selector).hash(&mut hasher);
+        rng.clone().next_u64().hash(&mut hasher);
+        grids.len().hash(&mut hasher);
+        for grid in grids {
+            grid.hash_state(&mut hasher);
+        }
+        for player in players {
+            player.is_some().hash(&mut hasher);
+            if let Some(player) = player {
+                player.hash_state(&mut hasher);
+            }
+        }
+        bullets.len().hash(&mut hasher);
+        for bullet in bullets {
+            bullet.hash_state(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    fn update_menu(&mut self) {
+        if self.in_menu {
+            let mut ready = true;
+            let mut player_count = 0;
+            for player in somes_mut(&mut self.players) {
+                ready &= player.ready;
+                player_count += 1;
+            }
+            if ready && player_count >= 2 {
+                self.in_menu = false;
+                self.menu_phase = MenuPhase::DropMenu;
+                for grid in &mut self.grids {
+                    if grid.state == GridState::Alive {
+                        grid.state = GridState::Dead;
+                    }
+                }
+                for player in somes_mut(&mut self.players) {
+                    player.alive = false;
+                    player.lives = PLAYER.max_lives;
+                }
+            }
+        }
+
+        match self.menu_phase {
+            MenuPhase::InMenu => (),
+            MenuPhase::DropMenu => {
+                if self
+                    .grids
+                    .iter()
+                    .all(|x| x.state == GridState::Alive && x.vel.y == 0.0)
+                {
+                    self.menu_phase = MenuPhase::InGame;
+                }
+            }
+            MenuPhase::InGame => (),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{fs, sync::Once};
+
+    use ggez::graphics::Point2;
+
+    use super::*;
+    use crate::config::{self, EXPLOSIVES};
+    use crate::grid::Tile;
+    use crate::pickup::{Pickup, PickupKind};
+
+    static LOAD_CONFIG: Once = Once::new();
+
+    /// Use the real config in tests. It's global, so it only gets loaded once
+    /// before any test reads it.
+    pub fn load_config() {
+        LOAD_CONFIG.call_once(|| {
+            let text = fs::read_to_string("resources/config.toml").unwrap();
+            config::load_str(&text).unwrap();
+        });
+    }
+
+    pub fn test_simulation(seed: u64) -> Simulation {
+        load_config();
+        let text = fs::read_to_string("resources/modules.txt").unwrap();
+        let modules = grid::parse_modules(&text).unwrap();
+        Simulation::new(modules, seed)
+    }
+
+    /// Two players that are ready to start, so the match begins right away
+    pub fn test_match(seed: u64) -> Simulation {
+        let mut sim = test_simulation(seed);
+        for slot in 0..2 {
+            let mut player = Player::new(Team(slot as u8));
+            player.ready = true;
+            sim.players[slot] = Some(player);
+        }
+        sim
+    }
+
+    /// Made up inputs for the first two slots that run, jump and shoot, always
+    /// the same for the same frame
+    pub fn scripted_inputs(frame: u32) -> Inputs {
+        let mut inputs = Inputs::default();
+        for (slot, input) in inputs.iter_mut().take(2).enumerate() {
+            let phase = frame / 20 + slot as u32 * 7;
+            input.lr = [-1.0, 0.0, 1.0][phase as usize % 3];
+            input.facing = if input.lr < 0.0 { -1.0 } else { 1.0 };
+            input.l_pressed = input.lr < 0.0;
+            input.r_pressed = input.lr > 0.0;
+            input.jump = phase % 4 == 0 && frame % 20 < 10;
+            input.shoot = frame % 12 < 6;
+            input.aim_y = [0.0, 1.0, 0.0, -1.0][phase as usize % 4];
+            input.aim_x = input.lr;
+        }
+        inputs
+    }
+
+    /// A match underway with a single player at `pos`, on a lowest grid made
+    /// of `rows` from the top down to its bottom row. The grids above are empty.
+    fn arena(rows: &[&str], pos: Point2) -> Simulation {
+        let mut sim = test_simulation(1);
+        let empty = [[Tile::Air; grid::GRID_WIDTH]; grid::GRID_HEIGHT];
+        let mut module = empty;
+        for (y, row) in rows.iter().rev().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                module[y][x] = Tile::from_char(c).unwrap();
+            }
+        }
+        sim.grids = vec![
+            Grid::new_from_module(0.0, module),
+            Grid::new_from_module(grid::GRID_HEIGHT as f32, empty),
+            Grid::new_from_module((grid::GRID_HEIGHT * 2) as f32, empty),
+        ];
+        sim.in_menu = false;
+        sim.menu_phase = MenuPhase::InGame;
+        let mut player = Player::new(Team(0));
+        player.alive = true;
+        player.set_physics(pos, Vector2::new(0.0, 0.0));
+        sim.players[0] = Some(player);
+        sim
+    }
+
+    fn player(sim: &Simulation) -> &Player {
+        sim.players[0].as_ref().unwrap()
+    }
+
+    const FLOOR: &str = "################################";
+
+    #[test]
+    fn matches_run_without_a_window() {
+        let mut sim = test_match(7);
+        for frame in 0..600 {
+            sim.step(&scripted_inputs(frame));
+        }
+        assert!(!sim.in_menu);
+        assert_eq!(sim.menu_phase, MenuPhase::InGame);
+        assert!(sim.players.iter().flatten().all(|player| player.alive));
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_the_same_match() {
+        let mut a = test_match(7);
+        let mut b = test_match(7);
+        for frame in 0..600 {
+            let inputs = scripted_inputs(frame);
+            let events_a = a.step(&inputs).to_vec();
+            let events_b = b.step(&inputs).to_vec();
+            assert_eq!(events_a, events_b, "events differ at frame {}", frame);
+            assert_eq!(a.checksum(), b.checksum(), "desync at frame {}", frame);
+        }
+        assert!(!a.in_menu);
+    }
+
+    /// A match with the player standing on the bottom grid, which just wore out
+    fn worn_out_match() -> Simulation {
+        let mut sim = arena(&[FLOOR], Point2::new(4.5, 1.0));
+        for _ in 0..GRID.tile_max_health {
+            sim.grids[0].damage_tile(30, 0);
+        }
+        assert!(sim.grids[0].percent_tiles_alive() < GRID.death_threshold);
+        sim
+    }
+
+    #[test]
+    fn worn_out_grids_fall_unless_repaired() {
+        let mut sim = worn_out_match();
+        let window = (GRID.repair_window / crate::DT) as u32;
+        // Half a second is plenty of time to fall without a chance to repair
+        for _ in 0..30 {
+            sim.step(&Inputs::default());
+        }
+        assert_eq!(sim.grids[0].state, GridState::Alive);
+        assert_eq!(sim.grids[0].height(), 0.0);
+        sim.grids[0].repair(u32::MAX, &[]);
+        for _ in 0..window {
+            sim.step(&Inputs::default());
+        }
+        assert_eq!(sim.grids[0].state, GridState::Alive);
+        assert_eq!(sim.grids[0].height(), 0.0);
+
+        let mut sim = worn_out_match();
+        for _ in 0..window + 10 {
+            sim.step(&Inputs::default());
+        }
+        assert!(sim.grids[0].height() < 0.0, "the grid never fell");
+
+        // Nobody is there to repair it
+        let mut sim = worn_out_match();
+        sim.players[0] = None;
+        for _ in 0..10 {
+            sim.step(&Inputs::default());
+        }
+        assert!(sim.grids[0].height() < 0.0, "the grid waited for nobody");
+    }
+
+    #[test]
+    fn different_seeds_give_different_matches() {
+        let mut a = test_match(1);
+        let mut b = test_match(2);
+        for frame in 0..300 {
+            let inputs = scripted_inputs(frame);
+            a.step(&inputs);
+            b.step(&inputs);
+        }
+        assert_ne!(a.checksum(), b.checksum());
+    }
+
+    #[test]
+    fn players_jump_up_through_one_way_platforms() {
+        let rows = ["==========", "", "", FLOOR];
+        let mut sim = arena(&rows, Point2::new(4.5, 1.0));
+        let mut inputs = Inputs::default();
+        inputs[0].jump = true;
+        for _ in 0..90 {
+            sim.step(&inputs);
+        }
+        // Standing on top of the platform
+        let height = player(&sim).pos().y;
+        assert!((height - 4.0).abs() < 0.1, "ended up at {}", height);
+    }
+
+    #[test]
+    fn hazards_hurt_once_per_invulnerable_window() {
+        let spikes = "^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^";
+        let mut sim = arena(&[spikes], Point2::new(4.5, 1.0));
+        let window = (PLAYER.invulnerable_time / crate::DT) as usize;
+        let mut hurt = Vec::new();
+        for tick in 0..window * 3 {
+            let health = player(&sim).health();
+            sim.step(&Inputs::default());
+            if player(&sim).health() < health {
+                hurt.push(tick);
+            }
+        }
+        assert!(hurt.len() >= 2, "hurt on ticks {:?}", hurt);
+        for pair in hurt.windows(2) {
+            assert!(pair[1] - pair[0] >= window, "hurt on ticks {:?}", hurt);
+        }
+    }
+
+    #[test]
+    fn bounce_pads_launch_players() {
+        let pads = "++++++++++++++++++++++++++++++++";
+        let mut sim = arena(&[pads], Point2::new(4.5, 1.0));
+        let mut highest = 0.0f32;
+        for _ in 0..60 {
+            sim.step(&Inputs::default());
+            highest = highest.max(player(&sim).pos().y);
+        }
+        // Higher than jumping gets them
+        let (single_jump, _) = crate::player::jump_heights();
+        assert!(highest > 1.0 + single_jump, "only got up to {}", highest);
+    }
+
+    #[test]
+    fn explosions_chain_into_the_grid_above() {
+        let mut sim = arena(
+            &["     *", "", "", "", "", "", "", FLOOR],
+            Point2::new(28.5, 1.0),
+        );
+        let mut module = [[Tile::Air; grid::GRID_WIDTH]; grid::GRID_HEIGHT];
+        for tile in module[0].iter_mut().take(20) {
+            *tile = Tile::from_char('*').unwrap();
+        }
+        sim.grids[1] = Grid::new_from_module(grid::GRID_HEIGHT as f32, module);
+
+        for _ in 0..EXPLOSIVES.health {
+            sim.grids[0].damage_tile(5, 7);
+        }
+        sim.step(&Inputs::default());
+        // The blast reaches the row above, which sets off the rest of it
+        assert!(sim.grids[1].module[0].iter().all(|&tile| tile == Tile::Air));
+    }
+
+    #[test]
+    fn players_collect_pickups_they_touch() {
+        let mut sim = arena(&[FLOOR], Point2::new(4.5, 1.0));
+        sim.players[0].as_mut().unwrap().set_health(1);
+        let pickup = Pickup {
+            kind: PickupKind::Health,
+            weapon: 0,
+            x: 4,
+            y: 1,
+        };
+        sim.grids[0].pickups.push(pickup);
+        sim.step(&Inputs::default());
+        assert_eq!(player(&sim).health(), PLAYER.max_health);
+        assert!(!sim.grids[0].pickups.contains(&pickup));
+    }
+
+    #[test]
+    fn piercing_bullets_break_through_tiles() {
+        let mut sim = arena(&["          ######", "", "", FLOOR], Point2::new(4.5, 1.0));
+        let piercer = weapon::find("piercer").unwrap();
+        let vel = Vector2::new(weapon::get(piercer).speed, 0.0);
+        let bullet = Bullet::new(Point2::new(2.0, 3.5), vel, Team(1), piercer);
+        let pierce = bullet.pierce as usize;
+        assert!(pierce > 0);
+        sim.bullets.push(bullet);
+        for _ in 0..30 {
+            sim.step(&Inputs::default());
+        }
+        // Through `pierce` tiles, then it stops in the one after
+        let row = &sim.grids[0].module[3][10..16];
+        assert!(row[..=pierce].iter().all(|&tile| tile == Tile::Air));
+        assert!(row[pierce + 1..].iter().all(|&tile| tile != Tile::Air));
+        assert!(sim.bullets.is_empty());
+    }
+
+    #[test]
+    fn bouncing_bullets_ricochet_off_bedrock() {
+        let mut sim = arena(&["          @", "", "", FLOOR], Point2::new(4.5, 1.0));
+        let bouncer = weapon::find("bouncer").unwrap();
+        let vel = Vector2::new(weapon::get(bouncer).speed, 0.0);
+        let bullet = Bullet::new(Point2::new(2.0, 3.5), vel, Team(1), bouncer);
+        let ricochets = bullet.ricochets;
+        sim.bullets.push(bullet);
+        for _ in 0..30 {
+            sim.step(&Inputs::default());
+        }
+        assert_eq!(sim.grids[0].module[3][10], Tile::Bedrock);
+        let bullet = &sim.bullets[0];
+        assert_eq!(bullet.ricochets, ricochets - 1);
+        assert!(bullet.vel.x < 0.0);
+        assert!(bullet.pos.x < 10.0);
+    }
+
+    #[test]
+    fn shots_knock_players_back_and_stun_them() {
+        let mut sim = arena(&[FLOOR], Point2::new(4.5, 1.0));
+        let blaster = weapon::find("blaster").unwrap();
+        let vel = Vector2::new(weapon::get(blaster).speed, 0.0);
+        let bullet = Bullet::new(Point2::new(1.0, 1.4), vel, Team(1), blaster);
+        sim.bullets.push(bullet);
+        while !sim.bullets.is_empty() {
+            sim.step(&Inputs::default());
+        }
+        let player = player(&sim);
+        assert!(player.health() < PLAYER.max_health);
+        assert!(player.stun > 0.0);
+        assert!(player.vel().x > 0.0);
+    }
+
+    #[test]
+    fn dashing_through_bullets_is_safe() {
+        let mut sim = arena(&[FLOOR], Point2::new(4.5, 1.0));
+        let blaster = weapon::find("blaster").unwrap();
+        let vel = Vector2::new(-weapon::get(blaster).speed, 0.0);
+        let bullet = Bullet::new(Point2::new(12.0, 1.4), vel, Team(1), blaster);
+        sim.bullets.push(bullet);
+        let mut inputs = Inputs::default();
+        inputs[0].dash = true;
+        inputs[0].lr = 1.0;
+        sim.step(&inputs);
+        assert!(player(&sim).invulnerable > 0.0);
+        while !sim.bullets.is_empty() {
+            sim.step(&inputs);
+        }
+        // The bullet ran into the player while they were still dashing
+        assert!(player(&sim).invulnerable > 0.0);
+        assert_eq!(player(&sim).health(), PLAYER.max_health);
+    }
+
+    #[test]
+    fn late_jumps_off_a_ledge_still_count_from_the_ground() {
+        let mut sim = arena(&["#####"], Point2::new(2.5, 1.0));
+        let mut inputs = Inputs::default();
+        inputs[0].lr = 1.0;
+        while player(&sim).grounded || player(&sim).frames_since_grounded < 2 {
+            assert!(player(&sim).pos().y > 0.5, "Never walked off the ledge");
+            sim.step(&inputs);
+        }
+        inputs[0].jump = true;
+        sim.step(&inputs);
+        assert!(player(&sim).vel().y > 0.0);
+        // Jumping from the ground leaves the second jump
+        assert!(player(&sim).can_jump());
+    }
+
+    #[test]
+    fn jumps_pressed_just_before_landing_happen_on_landing() {
+        let mut sim = arena(&[FLOOR], Point2::new(4.5, 1.0));
+        let mut inputs = Inputs::default();
+        // Use up both jumps
+        for _ in 0..2 {
+            inputs[0].jump = true;
+            sim.step(&inputs);
+            inputs[0].jump = false;
+            for _ in 0..10 {
+                sim.step(&inputs);
+            }
+        }
+        assert!(!player(&sim).can_jump());
+
+        let mut ticks = 0;
+        let mut falling = sim.clone();
+        while !falling.players[0].as_ref().unwrap().grounded {
+            falling.step(&inputs);
+            ticks += 1;
+        }
+        for _ in 0..ticks - 3 {
+            sim.step(&inputs);
+        }
+        inputs[0].jump = true;
+        for _ in 0..5 {
+            sim.step(&inputs);
+        }
+        assert!(player(&sim).vel().y > 0.0);
+    }
+}
@@ -6,6 +6,8 @@ use ggez::{
 };
 use rand::Rng;
 
+use crate::simulation::{self, GameRng};
+
 const GRID_FALL_OFFSCREEN: &str = "/gridfalldeath.ogg";
 const GRID_LAND: &str = "/gridfallland.ogg";
 const GRID_LAND_BOTTOM: &str = "/gridfallland2.ogg";
@@ -18,10 +20,16 @@ const LAND: &str = "/land1.ogg";
 
 const JUMP: &str = "/jump1.ogg";
 const SECOND_JUMP: &str = "/jump5.ogg";
+const PICKUP: &str = "/jump3.ogg";
+const DASH: &str = "/jump2.ogg";
+const REPAIR: &str = "/jump4.ogg";
 
 pub struct Sound {
     jump: SoundData,
     second_jump: SoundData,
+    pickup: SoundData,
+    dash: SoundData,
+    repair: SoundData,
     land: SoundData,
     shoot: Vec<SoundData>,
     break_block: SoundData,
@@ -31,13 +39,19 @@ pub struct Sound {
     grid_land: SoundData,
     grid_land_bottom: SoundData,
     sources: Vec<Source>,
+    // Kept apart from the simulation's generator so that playing audio never
+    // changes the outcome of a match
+    rng: GameRng,
 }
 
 impl Sound {
-    pub fn new(ctx: &mut Context) -> GameResult<Sound> {
+    pub fn new(ctx: &mut Context, seed: u64) -> GameResult<Sound> {
         Ok(Sound {
             jump: SoundData::new(ctx, JUMP)?,
             second_jump: SoundData::new(ctx, SECOND_JUMP)?,
+            pickup: SoundData::new(ctx, PICKUP)?,
+            dash: SoundData::new(ctx, DASH)?,
+            repair: SoundData::new(ctx, REPAIR)?,
             break_block: SoundData::new(ctx, BREAK_BLOCKS)?,
             shoot: to_sounds(ctx, SHOOT)?,
             land: SoundData::new(ctx, LAND)?,
@@ -47,6 +61,7 @@ impl Sound {
             grid_land: SoundData::new(ctx, GRID_LAND)?,
             grid_land_bottom: SoundData::new(ctx, GRID_LAND_BOTTOM)?,
             sources: vec![],
+            rng: simulation::new_rng(seed),
         })
     }
 
@@ -60,7 +75,10 @@ impl Sound {
         let (sound, volume) = match sound {
             Jump => (self.jump.clone(), 0.5),
             SecondJump => (self.second_jump.clone(), 0.4),
-            Shoot => (get_random(&self.shoot), 0.3),
+            Pickup => (self.pickup.clone(), 0.4),
+            Dash => (self.dash.clone(), 0.4),
+            Repair => (self.repair.clone(), 0.3),
+            Shoot => (get_random(&mut self.rng, &self.shoot), 0.3),
             Land => (self.land.clone(), 0.2),
             BreakBlock => (self.break_block.clone(), 0.5),
             DamageBlock => (self.damage_block_1.clone(), 0.2),
@@ -81,7 +99,7 @@ impl Sound {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum SoundEffect {
     Jump,
     SecondJump,
@@ -93,10 +111,13 @@ pub enum SoundEffect {
     GridFallOffscreen,
     GridLand,
     GridLandBottom,
+    Pickup,
+    Dash,
+    Repair,
 }
 
-fn get_random(sounds: &[SoundData]) -> SoundData {
-    rand::thread_rng().choose(&sounds).unwrap().clone()
+fn get_random(rng: &mut impl Rng, sounds: &[SoundData]) -> SoundData {
+    rng.choose(&sounds).unwrap().clone()
 }
 
 fn to_sounds<P: AsRef<path::Path>>(ctx: &mut Context, paths: &[P]) -> GameResult<Vec<SoundData>> {
@@ -0,0 +1,70 @@
+use crate::config::WEAPONS;
+
+/// What comes out of a gun, loaded from the `[[weapons.list]]` entries in
+/// the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct WeaponDef {
+    pub name: String,
+    /// Seconds between shots
+    pub cooldown: f32,
+    pub speed: f32,
+    /// How many bullets every shot fires, fanned out by `spread`
+    pub bullets: u32,
+    /// Degrees between neighbouring bullets of a shot
+    pub spread: f32,
+    /// Health taken from players that get hit
+    pub damage: u8,
+    /// Health taken from tiles that get hit
+    pub tile_damage: u8,
+    /// Seconds before a bullet fizzles out
+    pub lifetime: f32,
+    /// How many tiles a bullet can break through and keep going
+    pub pierce: u32,
+    /// How many times a bullet bounces off of walls that can't be broken
+    #[serde(default)]
+    pub ricochets: u32,
+    /// Speed players get pushed with along the bullet's direction when hit
+    #[serde(default)]
+    pub knockback: f32,
+    pub color: [f32; 4],
+}
+
+/// The original gun, for configs that don't list any weapons
+static BLASTER: WeaponDef = WeaponDef {
+    name: String::new(),
+    cooldown: 0.3,
+    speed: 30.0,
+    bullets: 1,
+    spread: 0.0,
+    damage: 1,
+    tile_damage: 2,
+    lifetime: 2.0,
+    pierce: 0,
+    ricochets: 0,
+    knockback: 6.0,
+    color: [1.0, 1.0, 1.0, 1.0],
+};
+
+pub fn get(index: usize) -> &'static WeaponDef {
+    WEAPONS.list.get(index).unwrap_or(&BLASTER)
+}
+
+pub fn find(name: &str) -> Option<usize> {
+    WEAPONS.list.iter().position(|x| x.name == name)
+}
+
+/// How many weapons there are to pick from, the built in one counts when the
+/// config doesn't list any
+pub fn count() -> usize {
+    WEAPONS.list.len().max(1)
+}
+
+/// The weapon players start with
+pub fn default_index() -> usize {
+    find(&WEAPONS.default).unwrap_or(0)
+}
+
+/// The weapon after this one, for picking one in the lobby
+pub fn next(index: usize) -> usize {
+    (index + 1) % count()
+}